@@ -0,0 +1,113 @@
+//! Gitignore-style allow/deny rules restricting which paths a guest can see.
+
+use anyhow::{Result, anyhow};
+use gix::{
+    bstr::{BStr, ByteSlice},
+    glob::{Pattern, pattern::Case, wildmatch},
+};
+use wasmtime_wasi::p2::{FsResult, bindings::filesystem::types::ErrorCode};
+
+/// What the guest sees when it tries to access a denied path.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DeniedBehaviour {
+    /// Denied paths don't exist as far as the guest can tell. They are left
+    /// out of directory listings and resolving them gives `NoEntry`.
+    #[default]
+    Hide,
+    /// Denied paths are listed, but opening or stat'ing them gives `Access`.
+    Forbid,
+}
+
+#[derive(Clone)]
+struct Rule {
+    pattern: Pattern,
+    // True for `!pattern` rules, which re-allow something denied earlier.
+    allow: bool,
+}
+
+/// A list of gitignore-style patterns, matched against paths relative to the
+/// mount root. As with `.gitignore` the last matching rule wins, and once a
+/// directory is denied nothing inside it can be re-allowed because path
+/// resolution never gets past it.
+///
+/// The default policy allows everything.
+#[derive(Clone, Default)]
+pub struct AccessPolicy {
+    rules: Vec<Rule>,
+    pub denied: DeniedBehaviour,
+}
+
+impl AccessPolicy {
+    pub fn new(denied: DeniedBehaviour) -> Self {
+        Self {
+            rules: Vec::new(),
+            denied,
+        }
+    }
+
+    /// Parse rules in `.gitignore` format: one pattern per line, `#` comments,
+    /// and `!` to allow a path again.
+    pub fn from_gitignore_format(text: &str, denied: DeniedBehaviour) -> Result<Self> {
+        let mut policy = Self::new(denied);
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let pattern = Pattern::from_bytes(line.as_bytes())
+                .ok_or_else(|| anyhow!("invalid access pattern {line:?}"))?;
+            policy.rules.push(Rule {
+                allow: pattern.is_negative(),
+                pattern,
+            });
+        }
+        Ok(policy)
+    }
+
+    /// Deny paths matching `pattern`.
+    pub fn deny(&mut self, pattern: &str) -> Result<&mut Self> {
+        self.push(pattern, false)
+    }
+
+    /// Allow paths matching `pattern`, overriding earlier `deny()` rules.
+    pub fn allow(&mut self, pattern: &str) -> Result<&mut Self> {
+        self.push(pattern, true)
+    }
+
+    fn push(&mut self, pattern: &str, allow: bool) -> Result<&mut Self> {
+        let pattern = Pattern::from_bytes_without_negation(pattern.as_bytes())
+            .ok_or_else(|| anyhow!("invalid access pattern {pattern:?}"))?;
+        self.rules.push(Rule { pattern, allow });
+        Ok(self)
+    }
+
+    /// `path` is relative to the mount root with no leading slash.
+    pub fn is_denied(&self, path: &BStr, is_dir: bool) -> bool {
+        let basename_start = path.rfind_byte(b'/').map(|p| p + 1);
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                rule.pattern.matches_repo_relative_path(
+                    path,
+                    basename_start,
+                    Some(is_dir),
+                    Case::Sensitive,
+                    wildmatch::Mode::NO_MATCH_SLASH_LITERAL,
+                )
+            })
+            .is_some_and(|rule| !rule.allow)
+    }
+
+    // Returns the configured error if `path` is denied.
+    pub(crate) fn check(&self, path: &BStr, is_dir: bool) -> FsResult<()> {
+        if self.is_denied(path, is_dir) {
+            return Err(match self.denied {
+                DeniedBehaviour::Hide => ErrorCode::NoEntry,
+                DeniedBehaviour::Forbid => ErrorCode::Access,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
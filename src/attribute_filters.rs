@@ -0,0 +1,230 @@
+//! Converting files the way `git checkout` would, according to
+//! `.gitattributes` (line endings, `ident` and `working-tree-encoding`).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use gix::{
+    ObjectId, Repository,
+    bstr::{BStr, BString, ByteSlice, ByteVec},
+    filter::plumbing::{
+        self as filter, driver::apply::Delay, pipeline::convert::ToWorktreeOutcome,
+    },
+    index::entry::Mode,
+    worktree::{self, stack::state::attributes::Source},
+};
+use wasmtime_wasi::p2::{FsResult, bindings::filesystem::types::ErrorCode};
+
+use crate::shared_repo::ObjectCache;
+
+// Total size of converted files kept, across all runs. Files that convert to
+// the same contents as the blob aren't kept, only the fact that they do.
+const CONVERTED_CACHE_BYTES: usize = 64 << 20;
+
+// Shared by all runs of a runner, so they share converted files.
+pub(crate) struct AttributeFilters {
+    // Cloned for each run, since converting a file needs them mutably.
+    pipeline: filter::Pipeline,
+    attributes: worktree::Stack,
+    // Where the mount root is in the repository, with a trailing slash
+    // unless it's the top. Paths from the guest are relative to the mount
+    // root but attributes are matched against paths relative to the top.
+    prefix: BString,
+    // Only locked to look files up and add them, not while converting.
+    converted: Mutex<ConvertedCache>,
+}
+
+impl AttributeFilters {
+    // `index` says where to find the `.gitattributes` files, and `source`
+    // whether to read them from the working tree or the object database.
+    // `subdir` is the mounted directory, or empty for the whole tree.
+    pub(crate) fn new(
+        repo: &Repository,
+        index: &gix::index::State,
+        source: Source,
+        subdir: &str,
+    ) -> Result<Self> {
+        let attributes = repo
+            .attributes_only(index, source)
+            .context("loading .gitattributes")?
+            .detach();
+
+        let mut options = gix::filter::Pipeline::options(repo).context("reading filter config")?;
+        // Filter drivers (e.g. Git LFS) run programs on the host, which we
+        // don't want guests to be able to trigger. Files that use them are
+        // passed through unconverted.
+        options.drivers.clear();
+        let pipeline = filter::Pipeline::new(repo.command_context()?, options);
+
+        let subdir = subdir.trim_matches('/');
+        let prefix = if subdir.is_empty() {
+            BString::default()
+        } else {
+            format!("{subdir}/").into()
+        };
+
+        Ok(Self {
+            pipeline,
+            attributes,
+            prefix,
+            converted: Mutex::default(),
+        })
+    }
+
+    // The filters for one run, which converts files without waiting for
+    // other runs.
+    pub(crate) fn for_run(self: &Arc<Self>) -> RunFilters {
+        RunFilters {
+            shared: self.clone(),
+            converter: Arc::new(Mutex::new(Converter {
+                pipeline: self.pipeline.clone(),
+                attributes: self.attributes.clone(),
+            })),
+        }
+    }
+
+    fn repo_path(&self, path: &BStr) -> BString {
+        let mut repo_path = self.prefix.clone();
+        repo_path.push_str(path);
+        repo_path
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RunFilters {
+    shared: Arc<AttributeFilters>,
+    // Clones of the pipeline and attribute stack, only used by this run.
+    converter: Arc<Mutex<Converter>>,
+}
+
+impl RunFilters {
+    // The converted contents of the blob `id` at `path`, if they're cached.
+    pub(crate) fn cached(&self, objects: &ObjectCache, path: &BStr, id: ObjectId) -> Option<Bytes> {
+        let key = (self.shared.repo_path(path), id);
+        match self.shared.converted.lock().unwrap().get(&key)? {
+            Converted::Changed(data) => Some(data),
+            Converted::Unchanged => objects.cached_blob(id),
+        }
+    }
+
+    // Convert the contents of the blob `id` at `path`.
+    pub(crate) fn convert(
+        &self,
+        repo: &Repository,
+        path: &BStr,
+        id: ObjectId,
+        data: Bytes,
+    ) -> FsResult<Bytes> {
+        let key = (self.shared.repo_path(path), id);
+        let cached = self.shared.converted.lock().unwrap().get(&key);
+        match cached {
+            Some(Converted::Changed(converted)) => return Ok(converted),
+            Some(Converted::Unchanged) => return Ok(data),
+            None => {}
+        }
+
+        let converted = self
+            .converter
+            .lock()
+            .unwrap()
+            .convert(repo, key.0.as_bstr(), &data)?;
+        let entry = match &converted {
+            Some(converted) => Converted::Changed(converted.clone()),
+            None => Converted::Unchanged,
+        };
+        self.shared.converted.lock().unwrap().insert(key, entry);
+        Ok(converted.unwrap_or(data))
+    }
+}
+
+struct Converter {
+    pipeline: filter::Pipeline,
+    attributes: worktree::Stack,
+}
+
+impl Converter {
+    // Convert `data` for `path`, relative to the top of the repository.
+    // Returns `None` if it doesn't change.
+    fn convert(
+        &mut self,
+        repo: &Repository,
+        path: &BStr,
+        data: &[u8],
+    ) -> Result<Option<Bytes>, ErrorCode> {
+        let platform = self
+            .attributes
+            .at_entry(path, Some(Mode::FILE), &repo.objects)
+            .map_err(|_| ErrorCode::Io)?;
+        let outcome = self
+            .pipeline
+            .convert_to_worktree(
+                data,
+                path,
+                &mut |_, attributes| {
+                    platform.matching_attributes(attributes);
+                },
+                Delay::Forbid,
+            )
+            // E.g. the content isn't valid in its `working-tree-encoding`.
+            .map_err(|_| ErrorCode::IllegalByteSequence)?;
+        Ok(match outcome {
+            ToWorktreeOutcome::Unchanged(_) => None,
+            ToWorktreeOutcome::Buffer(buffer) => Some(Bytes::copy_from_slice(buffer)),
+            // Only drivers give these, and there aren't any.
+            ToWorktreeOutcome::Process(_) => None,
+        })
+    }
+}
+
+#[derive(Clone)]
+enum Converted {
+    Changed(Bytes),
+    // The same as the blob, which is in the object cache (or can be loaded
+    // again).
+    Unchanged,
+}
+
+// Converted files by path and blob ID, since the same blob can convert
+// differently at different paths. When it's full the oldest files are
+// evicted first.
+#[derive(Default)]
+struct ConvertedCache {
+    files: HashMap<(BString, ObjectId), Converted>,
+    order: VecDeque<(BString, ObjectId)>,
+    bytes: usize,
+}
+
+impl ConvertedCache {
+    fn get(&self, key: &(BString, ObjectId)) -> Option<Converted> {
+        self.files.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: (BString, ObjectId), converted: Converted) {
+        if self.files.contains_key(&key) {
+            // Another load of the same file got there first.
+            return;
+        }
+        self.bytes += entry_size(&key, &converted);
+        self.order.push_back(key.clone());
+        self.files.insert(key, converted);
+        while self.bytes > CONVERTED_CACHE_BYTES
+            && let Some(key) = self.order.pop_front()
+        {
+            if let Some(converted) = self.files.remove(&key) {
+                self.bytes -= entry_size(&key, &converted);
+            }
+        }
+    }
+}
+
+fn entry_size((path, _): &(BString, ObjectId), converted: &Converted) -> usize {
+    let data = match converted {
+        Converted::Changed(data) => data.len(),
+        Converted::Unchanged => 0,
+    };
+    path.len() + data
+}
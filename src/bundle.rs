@@ -0,0 +1,115 @@
+//! Opening `git bundle` files by unpacking them into a temporary repository.
+
+use std::{
+    io::{BufRead, BufReader, Read as _},
+    path::Path,
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::{Context as _, Result, bail};
+use gix::{ObjectId, Repository};
+use tempfile::TempDir;
+
+// Whether the file at `path` looks like a bundle rather than a repository.
+pub(crate) fn is_bundle(path: &Path) -> bool {
+    let mut header = [0; 15];
+    path.is_file()
+        && std::fs::File::open(path)
+            .and_then(|mut file| file.read_exact(&mut header))
+            .is_ok()
+        && (&header == b"# v2 git bundle" || &header == b"# v3 git bundle")
+}
+
+// Unpack a bundle into a new bare repository in a temporary directory, with
+// the bundle's refs. Its pack is indexed but otherwise left as it is, so this
+// is about as quick as `git clone` from the bundle would be.
+//
+// The repository must not outlive the returned directory.
+pub(crate) fn open_bundle(path: &Path) -> Result<(Repository, TempDir)> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    if header != "# v2 git bundle\n" && header != "# v3 git bundle\n" {
+        bail!("{} is not a Git bundle", path.display());
+    }
+
+    let mut refs: Vec<(ObjectId, String)> = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end_matches('\n');
+        if line.is_empty() {
+            // The pack follows the blank line.
+            break;
+        }
+        if let Some(capability) = line.strip_prefix('@') {
+            // v3 only. Other capabilities (e.g. `filter`) don't change how
+            // we read the pack.
+            if let Some(format) = capability.strip_prefix("object-format=")
+                && format != "sha1"
+            {
+                bail!("unsupported bundle object format {format:?}");
+            }
+        } else if line.starts_with('-') {
+            // These would be the bases of a thin pack, which we can't resolve
+            // without the repository the bundle was made from.
+            bail!("incremental bundles (with prerequisite commits) aren't supported");
+        } else {
+            let (id, name) = line
+                .split_once(' ')
+                .with_context(|| format!("invalid bundle ref line {line:?}"))?;
+            refs.push((ObjectId::from_hex(id.as_bytes())?, name.to_string()));
+        }
+    }
+
+    // A new directory with a random name, so nothing else can have created
+    // it first.
+    let dir = tempfile::Builder::new()
+        .prefix("wasmtime_fs_demo_bundle_")
+        .tempdir()
+        .context("creating temporary directory for bundle")?;
+    gix::init_bare(dir.path()).context("creating repository for bundle")?;
+
+    gix::odb::pack::Bundle::write_to_directory(
+        &mut reader,
+        Some(&dir.path().join("objects/pack")),
+        &mut gix::progress::Discard,
+        &AtomicBool::new(false),
+        None::<gix::odb::Handle>,
+        gix::odb::pack::bundle::write::Options::default(),
+    )
+    .context("indexing bundle pack")?;
+
+    // Write the refs in the same format `git pack-refs` uses.
+    let mut packed_refs = String::from("# pack-refs with: sorted \n");
+    let mut head = None;
+    refs.sort_by(|a, b| a.1.cmp(&b.1));
+    for (id, name) in &refs {
+        if name == "HEAD" {
+            head = Some(id.to_string());
+        } else {
+            packed_refs.push_str(&format!("{id} {name}\n"));
+        }
+    }
+    std::fs::write(dir.path().join("packed-refs"), packed_refs)?;
+    // Bundles made with `git bundle create x.bundle --all` include HEAD, but
+    // otherwise point it at the first branch (or any ref) so that `HEAD`
+    // still resolves.
+    let head = match head {
+        Some(head) => head,
+        None => {
+            let (_, name) = refs
+                .iter()
+                .find(|(_, name)| name.starts_with("refs/heads/"))
+                .or(refs.first())
+                .context("bundle has no refs")?;
+            format!("ref: {name}")
+        }
+    };
+    std::fs::write(dir.path().join("HEAD"), format!("{head}\n"))?;
+
+    let repo = gix::open(dir.path()).context("opening unpacked bundle")?;
+    Ok((repo, dir))
+}
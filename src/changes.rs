@@ -0,0 +1,511 @@
+//! What a run with a writable filesystem changed, and exporting it as a
+//! patch, a tarball or files in a host directory.
+
+use std::{
+    fmt,
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use bytes::Bytes;
+use gix::{
+    ObjectId,
+    bstr::{BStr, BString, ByteSlice},
+    diff::blob::{Algorithm, diff, intern::InternedInput, sources::byte_lines_with_terminator},
+};
+
+use crate::shared_repo::SharedRepo;
+
+/// The type of a changed file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileMode {
+    Regular,
+    Executable,
+    Symlink,
+}
+
+impl FileMode {
+    // As written in trees and diffs.
+    fn git_mode(self) -> &'static str {
+        match self {
+            FileMode::Regular => "100644",
+            FileMode::Executable => "100755",
+            FileMode::Symlink => "120000",
+        }
+    }
+}
+
+/// A file that a run added, deleted or changed.
+#[derive(Clone, Debug)]
+pub struct ChangedFile {
+    /// Relative to the mount point.
+    pub path: BString,
+    /// The file's type and blob before the run, or `None` if it was added.
+    pub old: Option<(FileMode, ObjectId)>,
+    /// The file's type and contents after the run, or `None` if it was
+    /// deleted. For symlinks the contents are the target.
+    pub new: Option<(FileMode, Bytes)>,
+}
+
+/// Everything a run changed, compared to the tree it started with. See
+/// [`RunnerBuilder::writable()`](crate::RunnerBuilder::writable).
+///
+/// Only files are listed, sorted by path. Like in Git, directories only exist
+/// because of the files in them, so a new directory shows up as the files
+/// added to it and empty directories don't show up at all.
+#[derive(Clone)]
+pub struct Changes {
+    // For reading the old contents of files.
+    repo: SharedRepo,
+    files: Vec<ChangedFile>,
+}
+
+impl fmt::Debug for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Changes")
+            .field("files", &self.files)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Changes {
+    pub(crate) fn new(repo: SharedRepo, files: Vec<ChangedFile>) -> Self {
+        Self { repo, files }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn files(&self) -> &[ChangedFile] {
+        &self.files
+    }
+
+    /// The changes as a patch in `git diff` format, which `git apply` can
+    /// apply in the directory that was mounted.
+    pub fn unified_diff(&self) -> Result<Vec<u8>> {
+        let repo = self.repo.to_thread_local();
+        let mut out = Vec::new();
+        for file in &self.files {
+            let old = match file.old {
+                Some((mode, id)) => {
+                    let data = self
+                        .repo
+                        .objects()
+                        .blob(&repo, id)
+                        .map_err(|error| anyhow!("reading blob {id}: {error}"))?;
+                    Some((mode, data))
+                }
+                None => None,
+            };
+            let path = file.path.as_ref();
+            let old = old.as_ref().map(|(mode, data)| (*mode, data.as_ref()));
+            let new = file.new.as_ref().map(|(mode, data)| (*mode, data.as_ref()));
+            match (old, new) {
+                // Git shows replacing a file with a symlink (or the other way
+                // round) as deleting one and adding the other.
+                (Some(old), Some(new))
+                    if (old.0 == FileMode::Symlink) != (new.0 == FileMode::Symlink) =>
+                {
+                    write_file_diff(&mut out, path, Some(old), None);
+                    write_file_diff(&mut out, path, None, Some(new));
+                }
+                _ => write_file_diff(&mut out, path, old, new),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Write a tar archive of the files that were added or changed, in the
+    /// `ustar` format. Deleted files can't be represented, so they aren't in
+    /// it. Use [`files()`](Self::files) to find them.
+    pub fn write_tar(&self, mut out: impl Write) -> Result<()> {
+        for file in &self.files {
+            if let Some((mode, data)) = &file.new {
+                write_tar_entry(&mut out, file.path.as_ref(), *mode, data)
+                    .with_context(|| format!("adding {} to tar", file.path))?;
+            }
+        }
+        // The end of the archive is marked by two empty blocks.
+        out.write_all(&[0; 2 * TAR_BLOCK])?;
+        Ok(())
+    }
+
+    /// Apply the changes to a directory on the host, which should contain the
+    /// files the run started with, e.g. a checkout of the repository (or of
+    /// the mounted subdirectory). Directories left empty are removed, as Git
+    /// would.
+    ///
+    /// This is all or nothing: new contents are written to a staging
+    /// directory inside `dir` first, and the files they replace are moved
+    /// aside rather than deleted until everything is in place. If any step
+    /// fails, what was done so far is undone and `dir` is left as it was.
+    pub fn write_to_directory(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        let staging = tempfile::Builder::new()
+            .prefix(".wasmtime_fs_demo_staging_")
+            .tempdir_in(dir)
+            .with_context(|| format!("creating staging directory in {}", dir.display()))?;
+        let new = staging.path().join("new");
+        let old = staging.path().join("old");
+        std::fs::create_dir(&new)?;
+        std::fs::create_dir(&old)?;
+
+        let mut staged = Vec::new();
+        for (i, file) in self.files.iter().enumerate() {
+            if let Some((mode, data)) = &file.new {
+                let temp = new.join(i.to_string());
+                write_host_file(&temp, *mode, data)
+                    .with_context(|| format!("writing {}", file.path))?;
+                staged.push((temp, host_path(dir, file.path.as_ref())));
+            }
+        }
+
+        // Nothing in `dir` has changed up to here.
+        let mut undo = Vec::new();
+        let result = self.move_into_place(dir, &old, staged, &mut undo);
+        if result.is_err() {
+            for step in undo.into_iter().rev() {
+                // Carry on regardless, to put back as much as possible.
+                let _ = match step {
+                    Undo::Restore { from, to } => std::fs::rename(from, to),
+                    Undo::Remove(path) => std::fs::remove_file(path),
+                    Undo::RemoveDir(path) => std::fs::remove_dir(path),
+                    Undo::CreateDir(path) => std::fs::create_dir(path),
+                };
+            }
+        }
+        result
+    }
+
+    // The part of `write_to_directory()` that changes `dir`, recording how to
+    // undo each step in `undo`.
+    fn move_into_place(
+        &self,
+        dir: &Path,
+        old: &Path,
+        staged: Vec<(PathBuf, PathBuf)>,
+        undo: &mut Vec<Undo>,
+    ) -> Result<()> {
+        // Old files go first, in case files are being replaced by
+        // directories or the other way round.
+        for (i, file) in self.files.iter().enumerate() {
+            if file.old.is_none() {
+                continue;
+            }
+            let path = host_path(dir, file.path.as_ref());
+            let aside = old.join(i.to_string());
+            match std::fs::rename(&path, &aside) {
+                Ok(()) => {}
+                // Already gone, which is what we want.
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(error) => {
+                    return Err(error).with_context(|| format!("removing {}", path.display()));
+                }
+            }
+            undo.push(Undo::Restore {
+                from: aside,
+                to: path.clone(),
+            });
+            // Remove the directories containing it that are now empty.
+            let mut parent = path.parent();
+            while let Some(current) = parent
+                && current != dir
+                && current.starts_with(dir)
+                && std::fs::remove_dir(current).is_ok()
+            {
+                undo.push(Undo::CreateDir(current.to_path_buf()));
+                parent = current.parent();
+            }
+        }
+
+        for (temp, path) in staged {
+            if let Some(parent) = path.parent() {
+                // Create the missing directories one at a time so that they
+                // can be removed again.
+                let missing: Vec<_> = parent
+                    .ancestors()
+                    .take_while(|ancestor| !ancestor.exists())
+                    .collect();
+                for missing in missing.into_iter().rev() {
+                    std::fs::create_dir(missing)
+                        .with_context(|| format!("creating {}", missing.display()))?;
+                    undo.push(Undo::RemoveDir(missing.to_path_buf()));
+                }
+            }
+            std::fs::rename(&temp, &path)
+                .with_context(|| format!("moving {} into place", path.display()))?;
+            undo.push(Undo::Remove(path));
+        }
+        Ok(())
+    }
+}
+
+// How to undo one step of `Changes::write_to_directory()`.
+enum Undo {
+    // Move a file back to where it was.
+    Restore { from: PathBuf, to: PathBuf },
+    Remove(PathBuf),
+    RemoveDir(PathBuf),
+    CreateDir(PathBuf),
+}
+
+// Append the `git diff` output for one file. At least one of `old` and `new`
+// is set.
+fn write_file_diff(
+    out: &mut Vec<u8>,
+    path: &BStr,
+    old: Option<(FileMode, &[u8])>,
+    new: Option<(FileMode, &[u8])>,
+) {
+    out.extend_from_slice(format!("diff --git a/{path} b/{path}\n").as_bytes());
+    match (old, new) {
+        (None, Some((mode, _))) => {
+            out.extend_from_slice(format!("new file mode {}\n", mode.git_mode()).as_bytes());
+        }
+        (Some((mode, _)), None) => {
+            out.extend_from_slice(format!("deleted file mode {}\n", mode.git_mode()).as_bytes());
+        }
+        (Some((old_mode, _)), Some((new_mode, _))) if old_mode != new_mode => {
+            out.extend_from_slice(
+                format!(
+                    "old mode {}\nnew mode {}\n",
+                    old_mode.git_mode(),
+                    new_mode.git_mode()
+                )
+                .as_bytes(),
+            );
+        }
+        _ => {}
+    }
+
+    let old_data = old.map_or(&[][..], |(_, data)| data);
+    let new_data = new.map_or(&[][..], |(_, data)| data);
+    if old_data == new_data {
+        // Only the mode changed, or an empty file was added or deleted.
+        return;
+    }
+    let old_name = match old {
+        Some(_) => format!("a/{path}"),
+        None => "/dev/null".to_string(),
+    };
+    let new_name = match new {
+        Some(_) => format!("b/{path}"),
+        None => "/dev/null".to_string(),
+    };
+    if is_binary(old_data) || is_binary(new_data) {
+        out.extend_from_slice(
+            format!("Binary files {old_name} and {new_name} differ\n").as_bytes(),
+        );
+        return;
+    }
+    out.extend_from_slice(format!("--- {old_name}\n+++ {new_name}\n").as_bytes());
+    write_hunks(out, old_data, new_data);
+}
+
+// Like Git, a file is binary if there's a NUL byte near the start.
+fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(8000)].contains(&0)
+}
+
+// Lines of context around each change.
+const CONTEXT: u32 = 3;
+
+// Append the hunks of a unified diff between `old` and `new`.
+fn write_hunks(out: &mut Vec<u8>, old: &[u8], new: &[u8]) {
+    let input = InternedInput::new(
+        byte_lines_with_terminator(old),
+        byte_lines_with_terminator(new),
+    );
+    let mut changes: Vec<(Range<u32>, Range<u32>)> = Vec::new();
+    diff(Algorithm::Histogram, &input, |before, after| {
+        changes.push((before, after))
+    });
+
+    let old_len = input.before.len() as u32;
+    let mut rest = changes.as_slice();
+    while let Some((first, _)) = rest.first() {
+        // Changes closer together than twice the context go in one hunk.
+        let mut end = 1;
+        while end < rest.len() && rest[end].0.start - rest[end - 1].0.end <= 2 * CONTEXT {
+            end += 1;
+        }
+        let (hunk, remaining) = rest.split_at(end);
+        rest = remaining;
+
+        let lead = first.start.min(CONTEXT);
+        let last = &hunk[end - 1];
+        let trail = (old_len - last.0.end).min(CONTEXT);
+        let old_start = first.start - lead;
+        let new_start = hunk[0].1.start - lead;
+        let old_count = last.0.end + trail - old_start;
+        let new_count = last.1.end + trail - new_start;
+        out.extend_from_slice(
+            format!(
+                "@@ -{} +{} @@\n",
+                hunk_range(old_start, old_count),
+                hunk_range(new_start, new_count)
+            )
+            .as_bytes(),
+        );
+
+        let mut pos = old_start;
+        for (before, after) in hunk {
+            write_lines(
+                out,
+                b' ',
+                &input,
+                &input.before[pos as usize..before.start as usize],
+            );
+            write_lines(
+                out,
+                b'-',
+                &input,
+                &input.before[before.start as usize..before.end as usize],
+            );
+            write_lines(
+                out,
+                b'+',
+                &input,
+                &input.after[after.start as usize..after.end as usize],
+            );
+            pos = before.end;
+        }
+        write_lines(
+            out,
+            b' ',
+            &input,
+            &input.before[pos as usize..(pos + trail) as usize],
+        );
+    }
+}
+
+// A hunk header's `start,count`, where `start` is 1-based unless the range is
+// empty, in which case it's the line before. Git leaves out a count of 1.
+fn hunk_range(start: u32, count: u32) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+fn write_lines(
+    out: &mut Vec<u8>,
+    prefix: u8,
+    input: &InternedInput<&[u8]>,
+    tokens: &[gix::diff::blob::intern::Token],
+) {
+    for &token in tokens {
+        let line = input.interner[token];
+        out.push(prefix);
+        out.extend_from_slice(line);
+        if !line.ends_with(b"\n") {
+            out.extend_from_slice(b"\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+const TAR_BLOCK: usize = 512;
+
+// Write one `ustar` header, and the data for regular files.
+fn write_tar_entry(out: &mut impl Write, path: &BStr, mode: FileMode, data: &[u8]) -> Result<()> {
+    let mut header = [0u8; TAR_BLOCK];
+    let path: &[u8] = path;
+    // Paths longer than the name field are split at a slash into the prefix
+    // field and the name field.
+    let (prefix, name) = if path.len() <= 100 {
+        (&b""[..], path)
+    } else {
+        let split = path[..path.len().min(156)]
+            .rfind_byte(b'/')
+            .filter(|&split| path.len() - split - 1 <= 100)
+            .ok_or_else(|| anyhow!("path is too long for tar"))?;
+        (&path[..split], &path[split + 1..])
+    };
+    header[..name.len()].copy_from_slice(name);
+    header[345..345 + prefix.len()].copy_from_slice(prefix);
+
+    let (permissions, type_flag, size) = match mode {
+        FileMode::Regular => (0o644, b'0', data.len()),
+        FileMode::Executable => (0o755, b'0', data.len()),
+        FileMode::Symlink => {
+            if data.len() > 100 {
+                bail!("symlink target is too long for tar");
+            }
+            header[157..157 + data.len()].copy_from_slice(data);
+            (0o777, b'2', 0)
+        }
+    };
+    write_octal(&mut header[100..108], permissions);
+    // uid and gid.
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size as u64);
+    // Modification time. Git doesn't have one, and this keeps the output
+    // the same for the same changes.
+    write_octal(&mut header[136..148], 0);
+    header[156] = type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is calculated with the checksum field full of spaces.
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|&byte| u64::from(byte)).sum();
+    header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+
+    out.write_all(&header)?;
+    if type_flag == b'0' {
+        out.write_all(data)?;
+        let padding = (TAR_BLOCK - data.len() % TAR_BLOCK) % TAR_BLOCK;
+        out.write_all(&[0; TAR_BLOCK][..padding])?;
+    }
+    Ok(())
+}
+
+// Fill `field` with a NUL-terminated, zero-padded octal number.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}\0", width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+fn host_path(dir: &Path, path: &BStr) -> PathBuf {
+    dir.join(gix::path::from_bstr(path))
+}
+
+fn write_host_file(path: &Path, mode: FileMode, data: &[u8]) -> Result<()> {
+    match mode {
+        FileMode::Regular => std::fs::write(path, data)?,
+        FileMode::Executable => {
+            std::fs::write(path, data)?;
+            set_executable(path)?;
+        }
+        FileMode::Symlink => symlink(data, path)?,
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &[u8], path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(gix::path::from_bstr(target.as_bstr()), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &[u8], _path: &Path) -> Result<()> {
+    bail!("symlinks can only be written on Unix")
+}
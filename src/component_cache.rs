@@ -0,0 +1,93 @@
+//! On-disk cache of compiled components, so we don't have to recompile the
+//! same component every run.
+
+use std::{
+    fs,
+    hash::{DefaultHasher, Hash as _, Hasher as _},
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use wasmtime::{Engine, component::Component};
+
+pub struct ComponentCache {
+    dir: PathBuf,
+}
+
+impl ComponentCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    // Load the compiled form of `wasm` from the cache, or call `compile` and
+    // store the result. `wasm` is only used for the cache key. It is the
+    // component file as read, before `compile` adapts it if it's a WASIp1
+    // module. That's fine because the adapter comes from the same wasmtime
+    // release, which the engine's compatibility hash covers.
+    pub fn get_or_compile(
+        &self,
+        engine: &Engine,
+        wasm: &[u8],
+        compile: impl FnOnce() -> Result<Component>,
+    ) -> Result<Component> {
+        let path = self.dir.join(format!("{}.cwasm", cache_key(engine, wasm)?));
+
+        if path.exists() {
+            // SAFETY: Deserializing runs the machine code in the file without
+            // checking it, so the cache directory must only be writable by us.
+            // The key includes the engine's compatibility hash so we never load
+            // code compiled with different settings.
+            match unsafe { Component::deserialize_file(engine, &path) } {
+                Ok(component) => return Ok(component),
+                // Probably truncated or from a different wasmtime build.
+                // Recompile and overwrite it.
+                Err(error) => eprintln!("ignoring bad cache entry {}: {error}", path.display()),
+            }
+        }
+
+        let component = compile()?;
+
+        // Failing to write the cache shouldn't fail the run.
+        if let Err(error) = self.store(&path, &component) {
+            eprintln!("failed to write {}: {error:?}", path.display());
+        }
+        Ok(component)
+    }
+
+    fn store(&self, path: &Path, component: &Component) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        let serialized = component.serialize()?;
+        // Write to a temporary file and rename it into place so that concurrent
+        // runs never see a partially written entry. Every write gets its own
+        // file, even concurrent ones in the same process.
+        let mut temp = tempfile::NamedTempFile::new_in(&self.dir)
+            .with_context(|| format!("creating temporary file in {}", self.dir.display()))?;
+        temp.write_all(&serialized)
+            .with_context(|| format!("writing {}", temp.path().display()))?;
+        temp.persist(path)
+            .with_context(|| format!("renaming to {}", path.display()))?;
+        Ok(())
+    }
+}
+
+// SHA-1 of the component file plus a hash of the wasmtime version and the
+// engine settings that affect the generated code (target, fuel, epoch
+// interruption etc.).
+fn cache_key(engine: &Engine, wasm: &[u8]) -> Result<String> {
+    let mut wasm_hasher = gix::hash::hasher(gix::hash::Kind::Sha1);
+    wasm_hasher.update(wasm);
+    let wasm_hash = wasm_hasher.try_finalize()?;
+
+    let mut engine_hasher = DefaultHasher::new();
+    engine
+        .precompile_compatibility_hash()
+        .hash(&mut engine_hasher);
+
+    Ok(format!(
+        "{}-{:016x}",
+        wasm_hash.to_hex(),
+        engine_hasher.finish()
+    ))
+}
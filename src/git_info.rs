@@ -0,0 +1,100 @@
+//! A virtual `.gitinfo/` directory telling guests which commit they are
+//! looking at.
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use gix::{
+    ObjectId, Repository,
+    bstr::{BString, ByteVec},
+    objs::{self, tree::EntryKind},
+};
+
+use crate::{index_tree, shared_repo::ObjectCache};
+
+// The name of the directory, in the root of the tree. Guests can't change it
+// even if the rest of the tree is writable.
+pub(crate) const DIR_NAME: &str = ".gitinfo";
+
+// Return a copy of the `root` tree with a `.gitinfo/` directory added,
+// containing:
+//
+// * `HEAD`: like `.git/HEAD`, i.e. `ref: <branch>` or a commit ID if detached.
+// * `commit`: the ID of the commit `rev` resolves to.
+// * `tree`: the ID of the tree `rev` resolves to. This isn't `root` if
+//   `root` has been filtered.
+// * `author`: the commit's author, in Git's raw `Name <email> time tz` format.
+// * `message`: the full commit message.
+// * `refs`: the names of the refs that point at the commit, one per line.
+//
+// `commit`, `author` and `message` are left out if `rev` is a tree rather
+// than a commit. If the tree already has a `.gitinfo` it is hidden.
+pub(crate) fn with_git_info(
+    repo: &Repository,
+    objects: &ObjectCache,
+    root: ObjectId,
+    rev: &str,
+) -> Result<ObjectId> {
+    let mut files: Vec<(&str, Vec<u8>)> = Vec::new();
+
+    let head = match repo.head_name()? {
+        Some(name) => format!("ref: {}\n", name.as_bstr()),
+        None => format!("{}\n", repo.head_id()?),
+    };
+    files.push(("HEAD", head.into_bytes()));
+
+    let object = repo.rev_parse_single(rev)?.object()?.peel_tags_to_end()?;
+    let tree = object.clone().peel_to_tree()?.id;
+    files.push(("tree", format!("{tree}\n").into_bytes()));
+
+    let commit = object.try_into_commit().ok();
+    if let Some(commit) = commit {
+        files.push(("commit", format!("{}\n", commit.id).into_bytes()));
+
+        let mut author = Vec::new();
+        commit.author()?.write_to(&mut author)?;
+        author.push(b'\n');
+        files.push(("author", author));
+
+        files.push(("message", commit.message_raw()?.to_vec()));
+
+        let mut refs = Vec::new();
+        for reference in repo.references()?.all()? {
+            let mut reference = reference.map_err(|error| anyhow!(error))?;
+            // Refs that can't be peeled (e.g. dangling symrefs) can't point
+            // at the commit, so ignore them rather than failing.
+            if reference
+                .peel_to_id_in_place()
+                .is_ok_and(|id| id == commit.id)
+            {
+                refs.push(reference.name().as_bstr().to_owned());
+            }
+        }
+        refs.sort();
+        let mut refs_file = Vec::new();
+        for name in refs {
+            refs_file.push_str(name);
+            refs_file.push(b'\n');
+        }
+        files.push(("refs", refs_file));
+    }
+
+    let hash_kind = repo.object_hash();
+    let existing = objects
+        .tree(repo, root)
+        .map_err(|error| anyhow!("reading tree {root}: {error}"))?;
+    let mut entries: Vec<_> = existing
+        .iter()
+        .filter(|entry| entry.name != DIR_NAME)
+        .map(|entry| (entry.name.clone(), entry.kind, entry.id))
+        .collect();
+    for (name, data) in files {
+        let id = objs::compute_hash(hash_kind, objs::Kind::Blob, &data)?;
+        objects.insert_blob(id, Bytes::from(data));
+        entries.push((
+            BString::from(format!("{DIR_NAME}/{name}")),
+            EntryKind::Blob,
+            id,
+        ));
+    }
+    index_tree::tree_from_entries(entries, hash_kind, objects)
+}
@@ -0,0 +1,115 @@
+//! Build a root tree from the index (staging area), so guests can see exactly
+//! what would be committed.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context as _, Result, bail};
+use gix::{
+    ObjectId, Repository,
+    bstr::{BString, ByteSlice},
+    index::entry::{Flags, Stage},
+    objs::{self, WriteTo as _, tree::EntryKind},
+};
+
+use crate::shared_repo::{ObjectCache, TreeEntry};
+
+// A directory while we're building it.
+#[derive(Default)]
+struct Dir {
+    // Files, symlinks, submodules, and (in sparse indexes) whole trees.
+    entries: Vec<(BString, EntryKind, ObjectId)>,
+    subdirs: BTreeMap<BString, Dir>,
+}
+
+// Build the tree `git write-tree` would write for the current index and
+// return its ID. Nothing is written to the repository. Instead the trees go
+// straight into the object cache, and because they are keyed by their real
+// IDs they can't clash with anything already there.
+//
+// Like `git write-tree`:
+//
+// * Intent-to-add entries (`git add -N`) are left out because they have no
+//   staged content yet.
+// * Unmerged entries (stages 1-3) are an error.
+pub(crate) fn tree_from_index(repo: &Repository, objects: &ObjectCache) -> Result<ObjectId> {
+    let index = repo.index_or_empty().context("reading index")?;
+
+    let mut entries = Vec::new();
+    for entry in index.entries() {
+        let path = entry.path(&index);
+        if entry.stage() != Stage::Unconflicted {
+            bail!("index has unresolved conflicts in {path}");
+        }
+        if entry.flags.contains(Flags::INTENT_TO_ADD) {
+            continue;
+        }
+        let kind = entry
+            .mode
+            .to_tree_entry_mode()
+            .with_context(|| format!("unsupported index entry mode for {path}"))?
+            .kind();
+        entries.push((path.to_owned(), kind, entry.id));
+    }
+
+    tree_from_entries(entries, repo.object_hash(), objects)
+}
+
+// Build trees containing `entries`, which are full paths relative to the
+// root, put them in the object cache, and return the root tree's ID.
+pub(crate) fn tree_from_entries(
+    entries: impl IntoIterator<Item = (BString, EntryKind, ObjectId)>,
+    hash_kind: gix::hash::Kind,
+    objects: &ObjectCache,
+) -> Result<ObjectId> {
+    let mut root = Dir::default();
+    for (path, kind, id) in entries {
+        let mut dir = &mut root;
+        let mut components = path.split_str("/").peekable();
+        while let Some(name) = components.next() {
+            if components.peek().is_none() {
+                dir.entries.push((name.into(), kind, id));
+            } else {
+                dir = dir.subdirs.entry(name.into()).or_default();
+            }
+        }
+    }
+    write_tree(root, hash_kind, objects)
+}
+
+fn write_tree(dir: Dir, hash_kind: gix::hash::Kind, objects: &ObjectCache) -> Result<ObjectId> {
+    let mut tree = objs::Tree::empty();
+    for (filename, kind, oid) in dir.entries {
+        tree.entries.push(objs::tree::Entry {
+            mode: kind.into(),
+            filename,
+            oid,
+        });
+    }
+    for (filename, subdir) in dir.subdirs {
+        tree.entries.push(objs::tree::Entry {
+            mode: EntryKind::Tree.into(),
+            oid: write_tree(subdir, hash_kind, objects)?,
+            filename,
+        });
+    }
+    // Git sorts directories as if their names end in `/`, which `Entry`'s
+    // `Ord` does for us.
+    tree.entries.sort();
+
+    let mut data = Vec::new();
+    tree.write_to(&mut data)?;
+    let id = objs::compute_hash(hash_kind, objs::Kind::Tree, &data)?;
+
+    objects.insert_tree(
+        id,
+        tree.entries
+            .into_iter()
+            .map(|entry| TreeEntry {
+                name: entry.filename,
+                kind: entry.mode.kind(),
+                id: entry.oid,
+            })
+            .collect(),
+    );
+    Ok(id)
+}
@@ -0,0 +1,453 @@
+//! Reading parts of large blobs without inflating all of them at once.
+//!
+//! Blobs at least `LARGE_BLOB_SIZE` bytes are read in `CHUNK_SIZE` chunks by
+//! inflating their zlib stream directly, from the loose object file or from
+//! the memory-mapped pack. Chunks go in a cache of limited size, so reading
+//! at a large offset only holds a few chunks in memory, and reading the same
+//! part again doesn't inflate it again.
+//!
+//! Delta-compressed pack entries can't be streamed like this, so those (and
+//! objects in alternates) are still inflated completely.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, BufReader, Read},
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use bytes::Bytes;
+use flate2::read::ZlibDecoder;
+use gix::{ObjectId, Repository, odb::pack};
+use wasmtime_wasi::p2::bindings::filesystem::types::ErrorCode;
+
+/// Blobs at least this big are read in chunks.
+pub(crate) const LARGE_BLOB_SIZE: u64 = 64 << 20;
+const CHUNK_SIZE: u64 = 1 << 20;
+// Total size of cached chunks, across all blobs.
+const CHUNK_CACHE_BYTES: usize = 256 << 20;
+
+#[derive(Default)]
+pub(crate) struct LargeBlobs {
+    // `None` for large blobs that can't be streamed.
+    blobs: Mutex<HashMap<ObjectId, Option<Arc<LargeBlob>>>>,
+    chunks: Mutex<ChunkCache>,
+    // Opened lazily, the first time a large blob is read.
+    packs: OnceLock<Vec<Arc<pack::Bundle>>>,
+}
+
+impl LargeBlobs {
+    // Read from `offset` to the end of the chunk containing it. Returns
+    // `Ok(None)` if the blob can't be streamed, in which case the caller
+    // should read it all.
+    pub(crate) fn read_chunk(
+        &self,
+        repo: &Repository,
+        id: ObjectId,
+        offset: u64,
+    ) -> Result<Option<Bytes>, ErrorCode> {
+        if let Some(chunk) = self.cached_chunk(id, offset) {
+            return Ok(Some(chunk));
+        }
+        let Some(blob) = self.blob(repo, id) else {
+            return Ok(None);
+        };
+        let index = offset / CHUNK_SIZE;
+        let chunk = blob.read_chunk(index, &self.chunks).map_err(|error| {
+            eprintln!("Error reading Git object {id}: {error}");
+            ErrorCode::Io
+        })?;
+        Ok(Some(from_offset(chunk, offset - index * CHUNK_SIZE)))
+    }
+
+    // Like `read_chunk()` but only if the chunk is already inflated.
+    pub(crate) fn cached_chunk(&self, id: ObjectId, offset: u64) -> Option<Bytes> {
+        let index = offset / CHUNK_SIZE;
+        let chunk = self.chunks.lock().unwrap().get(id, index)?;
+        Some(from_offset(chunk, offset - index * CHUNK_SIZE))
+    }
+
+    fn blob(&self, repo: &Repository, id: ObjectId) -> Option<Arc<LargeBlob>> {
+        if let Some(blob) = self.blobs.lock().unwrap().get(&id) {
+            return blob.clone();
+        }
+        let blob = self.locate(repo, id).map(|(source, size)| {
+            Arc::new(LargeBlob {
+                id,
+                source,
+                size,
+                cursor: Mutex::new(None),
+            })
+        });
+        self.blobs.lock().unwrap().insert(id, blob.clone());
+        blob
+    }
+
+    // Find where the compressed data is, and the blob's size.
+    fn locate(&self, repo: &Repository, id: ObjectId) -> Option<(Source, u64)> {
+        let objects_dir = repo.common_dir().join("objects");
+        let hex = id.to_hex().to_string();
+        let loose = objects_dir.join(&hex[..2]).join(&hex[2..]);
+        if loose.is_file() {
+            let size = repo.find_header(id).ok()?.size();
+            return Some((Source::Loose(loose), size));
+        }
+
+        let packs = self
+            .packs
+            .get_or_init(|| open_packs(repo, &objects_dir.join("pack")));
+        for bundle in packs {
+            let Some(index) = bundle.index.lookup(id) else {
+                continue;
+            };
+            let entry = bundle
+                .pack
+                .entry(bundle.index.pack_offset_at_index(index))
+                .ok()?;
+            // Anything else is a delta.
+            if !matches!(entry.header, pack::data::entry::Header::Blob) {
+                return None;
+            }
+            return Some((
+                Source::Pack {
+                    bundle: bundle.clone(),
+                    data_offset: entry.data_offset,
+                },
+                entry.decompressed_size,
+            ));
+        }
+        None
+    }
+}
+
+// The part of `data` from `offset` on, which is empty if it's past the end.
+pub(crate) fn from_offset(data: Bytes, offset: u64) -> Bytes {
+    let start = usize::try_from(offset)
+        .unwrap_or(usize::MAX)
+        .min(data.len());
+    data.slice(start..)
+}
+
+fn open_packs(repo: &Repository, pack_dir: &std::path::Path) -> Vec<Arc<pack::Bundle>> {
+    let Ok(entries) = std::fs::read_dir(pack_dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "idx"))
+        .filter_map(|path| pack::Bundle::at(path, repo.object_hash()).ok())
+        .map(Arc::new)
+        .collect()
+}
+
+// Where a blob's zlib-compressed data is.
+enum Source {
+    Loose(PathBuf),
+    // A non-delta entry in a pack.
+    Pack {
+        bundle: Arc<pack::Bundle>,
+        data_offset: u64,
+    },
+}
+
+struct LargeBlob {
+    id: ObjectId,
+    source: Source,
+    size: u64,
+    // Where we got to inflating the blob, so reading it in order only
+    // inflates it once.
+    cursor: Mutex<Option<Cursor>>,
+}
+
+struct Cursor {
+    // Inflated data, starting at `pos`.
+    reader: Box<dyn Read + Send>,
+    pos: u64,
+}
+
+impl LargeBlob {
+    fn read_chunk(&self, index: u64, chunks: &Mutex<ChunkCache>) -> io::Result<Bytes> {
+        let start = index * CHUNK_SIZE;
+        let mut cursor = self.cursor.lock().unwrap();
+        // Zlib streams can only be read forwards, so start again from the
+        // beginning if we've gone past it.
+        if cursor.as_ref().is_none_or(|cursor| cursor.pos > start) {
+            *cursor = Some(Cursor {
+                reader: self.open()?,
+                pos: 0,
+            });
+        }
+        let cursor = cursor.as_mut().unwrap();
+
+        // Cache the chunks we skip over too since we had to inflate them
+        // anyway.
+        loop {
+            let len = CHUNK_SIZE.min(self.size.saturating_sub(cursor.pos));
+            if len == 0 {
+                // Past the end.
+                return Ok(Bytes::new());
+            }
+            let mut chunk = vec![0; len as usize];
+            cursor.reader.read_exact(&mut chunk)?;
+            let chunk = Bytes::from(chunk);
+            let chunk_index = cursor.pos / CHUNK_SIZE;
+            cursor.pos += len;
+            chunks
+                .lock()
+                .unwrap()
+                .insert(self.id, chunk_index, chunk.clone());
+            if chunk_index == index {
+                return Ok(chunk);
+            }
+        }
+    }
+
+    // Open the blob's data for reading from the start.
+    fn open(&self) -> io::Result<Box<dyn Read + Send>> {
+        match &self.source {
+            Source::Loose(path) => {
+                let mut reader = ZlibDecoder::new(BufReader::new(File::open(path)?));
+                // Skip the `blob <size>\0` header.
+                let mut byte = [0xff];
+                while byte[0] != 0 {
+                    reader.read_exact(&mut byte)?;
+                }
+                Ok(Box::new(reader))
+            }
+            Source::Pack {
+                bundle,
+                data_offset,
+            } => Ok(Box::new(ZlibDecoder::new(PackReader {
+                bundle: bundle.clone(),
+                pos: *data_offset,
+            }))),
+        }
+    }
+}
+
+// Reads the raw bytes of a (memory-mapped) pack file from `pos` onwards.
+struct PackReader {
+    bundle: Arc<pack::Bundle>,
+    pos: u64,
+}
+
+impl Read for PackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = (self.bundle.pack.pack_end() as u64).min(self.pos + buf.len() as u64);
+        let Some(data) = self.bundle.pack.entry_slice(self.pos..end) else {
+            return Ok(0);
+        };
+        buf[..data.len()].copy_from_slice(data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+// Chunks by blob ID and chunk index. When it's full the oldest chunks are
+// evicted first.
+struct ChunkCache {
+    chunks: HashMap<(ObjectId, u64), Bytes>,
+    order: VecDeque<(ObjectId, u64)>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Default for ChunkCache {
+    fn default() -> Self {
+        Self::new(CHUNK_CACHE_BYTES)
+    }
+}
+
+impl ChunkCache {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            chunks: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn get(&self, id: ObjectId, index: u64) -> Option<Bytes> {
+        self.chunks.get(&(id, index)).cloned()
+    }
+
+    fn insert(&mut self, id: ObjectId, index: u64, chunk: Bytes) {
+        self.bytes += chunk.len();
+        if let Some(old) = self.chunks.insert((id, index), chunk) {
+            self.bytes -= old.len();
+        } else {
+            self.order.push_back((id, index));
+        }
+        while self.bytes > self.max_bytes
+            && let Some(key) = self.order.pop_front()
+        {
+            if let Some(chunk) = self.chunks.remove(&key) {
+                self.bytes -= chunk.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command};
+
+    use super::*;
+
+    fn id(n: u8) -> ObjectId {
+        ObjectId::from_bytes_or_panic(&[n; 20])
+    }
+
+    #[test]
+    fn chunk_cache_evicts_oldest_first() {
+        let mut cache = ChunkCache::new(10);
+        cache.insert(id(1), 0, Bytes::from(vec![1; 4]));
+        cache.insert(id(1), 1, Bytes::from(vec![2; 4]));
+        assert_eq!(cache.bytes, 8);
+        cache.insert(id(2), 0, Bytes::from(vec![3; 4]));
+        assert_eq!(cache.bytes, 8);
+        assert!(cache.get(id(1), 0).is_none());
+        assert!(cache.get(id(1), 1).is_some());
+        assert!(cache.get(id(2), 0).is_some());
+
+        // Replacing a chunk only counts the new one, and doesn't move it
+        // to the back of the queue.
+        cache.insert(id(1), 1, Bytes::from(vec![4; 2]));
+        assert_eq!(cache.bytes, 6);
+        cache.insert(id(3), 0, Bytes::from(vec![5; 5]));
+        assert!(cache.get(id(1), 1).is_none());
+        assert_eq!(cache.bytes, 9);
+        assert_eq!(cache.order.len(), cache.chunks.len());
+    }
+
+    // 2.5 chunks of data that doesn't compress much, and differs from the
+    // output for other seeds.
+    fn contents(seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..CHUNK_SIZE * 5 / 2)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    // A repository with the given files committed, all loose.
+    fn repo(files: &[(&str, &[u8])]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "--quiet"]);
+        for (name, data) in files {
+            std::fs::write(dir.path().join(name), data).unwrap();
+        }
+        git(dir.path(), &["add", "--all"]);
+        git(
+            dir.path(),
+            &[
+                "-c",
+                "user.name=Test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "--message",
+                "Add files",
+            ],
+        );
+        dir
+    }
+
+    fn blob_id(repo: &Repository, path: &str) -> ObjectId {
+        repo.rev_parse_single(format!("HEAD:{path}").as_str())
+            .unwrap()
+            .detach()
+    }
+
+    // Read the blob at various offsets and check against `data`.
+    fn check_reads(blobs: &LargeBlobs, repo: &Repository, id: ObjectId, data: &[u8]) {
+        let read = |offset: u64| blobs.read_chunk(repo, id, offset).unwrap().unwrap();
+        let chunk = CHUNK_SIZE as usize;
+
+        assert_eq!(read(0), data[..chunk]);
+        // Reads stop at the end of the chunk.
+        assert_eq!(read(CHUNK_SIZE - 10), data[chunk - 10..chunk]);
+        assert_eq!(read(CHUNK_SIZE), data[chunk..2 * chunk]);
+        assert_eq!(read(CHUNK_SIZE + 5), data[chunk + 5..2 * chunk]);
+        // The last chunk is shorter.
+        assert_eq!(read(2 * CHUNK_SIZE), data[2 * chunk..]);
+        assert_eq!(read(data.len() as u64 - 1), data[data.len() - 1..]);
+        // At and past the end.
+        assert!(read(data.len() as u64).is_empty());
+        assert!(read(data.len() as u64 + 1).is_empty());
+        assert!(read(10 * CHUNK_SIZE).is_empty());
+    }
+
+    fn is_loose(blobs: &LargeBlobs, id: ObjectId) -> bool {
+        let blobs = blobs.blobs.lock().unwrap();
+        let blob = blobs[&id].as_ref().unwrap();
+        matches!(blob.source, Source::Loose(_))
+    }
+
+    #[test]
+    fn reads_loose_blobs() {
+        let data = contents(1);
+        let dir = repo(&[("big", &data)]);
+        let repo = gix::open(dir.path()).unwrap();
+        let id = blob_id(&repo, "big");
+
+        let blobs = LargeBlobs::default();
+        check_reads(&blobs, &repo, id, &data);
+        assert!(is_loose(&blobs, id));
+    }
+
+    #[test]
+    fn reads_packed_blobs() {
+        let data = contents(2);
+        let dir = repo(&[("big", &data)]);
+        git(dir.path(), &["repack", "--quiet", "-a", "-d"]);
+        let repo = gix::open(dir.path()).unwrap();
+        let id = blob_id(&repo, "big");
+
+        let blobs = LargeBlobs::default();
+        check_reads(&blobs, &repo, id, &data);
+        assert!(!is_loose(&blobs, id));
+
+        // The cursor is past the start, so reading it again once it's no
+        // longer cached inflates the blob from the beginning again.
+        *blobs.chunks.lock().unwrap() = ChunkCache::default();
+        assert_eq!(
+            blobs.read_chunk(&repo, id, 1).unwrap().unwrap(),
+            data[1..CHUNK_SIZE as usize]
+        );
+    }
+
+    #[test]
+    fn deltas_are_not_streamed() {
+        let data = contents(3);
+        let mut changed = data.clone();
+        changed[CHUNK_SIZE as usize..][..100].fill(0);
+        let dir = repo(&[("a", &data), ("b", &changed)]);
+        // Forces one of them to be stored as a delta of the other.
+        git(dir.path(), &["repack", "--quiet", "-a", "-d", "-f"]);
+        let repo = gix::open(dir.path()).unwrap();
+
+        let blobs = LargeBlobs::default();
+        let streamed: Vec<_> = [blob_id(&repo, "a"), blob_id(&repo, "b")]
+            .into_iter()
+            .map(|id| blobs.read_chunk(&repo, id, 0).unwrap().is_some())
+            .collect();
+        // Whichever is the base can still be streamed.
+        assert_eq!(streamed.iter().filter(|streamed| **streamed).count(), 1);
+    }
+}
@@ -0,0 +1,33 @@
+//! Run WASI components with a Git commit as their filesystem, using a custom
+//! wasi-filesystem implementation instead of Wasmtime's default one.
+
+mod access_policy;
+mod attribute_filters;
+mod bundle;
+mod changes;
+mod component_cache;
+mod git_info;
+mod index_tree;
+mod large_blob;
+mod limits;
+mod overlay;
+mod persist;
+mod preview1;
+mod quota;
+mod runner;
+mod shared_repo;
+mod side_by_side;
+mod sparse;
+mod wasi_linker_excluding_filesystem;
+mod wasi_state;
+mod wasi_state_sync;
+mod worktree_tree;
+
+pub use access_policy::{AccessPolicy, DeniedBehaviour};
+pub use changes::{ChangedFile, Changes, FileMode};
+pub use limits::{GuestLimits, LimitExceeded};
+pub use persist::Persist;
+pub use quota::{FsQuota, FsUsage};
+pub use runner::{RunOutcome, RunOutput, Runner, RunnerBuilder};
+pub use shared_repo::SharedRepo;
+pub use sparse::SparsePatterns;
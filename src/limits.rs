@@ -0,0 +1,118 @@
+//! Hard limits on guest memory, tables and instances.
+
+use std::fmt;
+
+use wasmtime::ResourceLimiter;
+
+/// Guest resource limits for a single run. `None` means wasmtime's default.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GuestLimits {
+    /// Total bytes of linear memory across all memories in the component.
+    /// Components usually contain several core modules (e.g. the WASIp1
+    /// adapter has its own memory) so a per-memory limit isn't much use.
+    pub max_memory_bytes: Option<usize>,
+    /// Maximum number of elements in any one table.
+    pub max_table_elements: Option<usize>,
+    /// Maximum number of core instances.
+    pub max_instances: Option<usize>,
+}
+
+/// The trap error when a guest tries to go over its [`GuestLimits`].
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub resource: &'static str,
+    pub limit: usize,
+    pub desired: usize,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "guest exceeded its {} limit: wanted {} but the limit is {}",
+            self.resource, self.desired, self.limit
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+pub(crate) struct GuestLimiter {
+    limits: GuestLimits,
+    // Total linear memory allocated so far. Memories can't shrink so this
+    // only goes up, unless a growth we allowed then fails.
+    memory_bytes: usize,
+    // How much the last allowed growth added to `memory_bytes`, to take off
+    // again if it fails.
+    last_growth: usize,
+}
+
+impl GuestLimiter {
+    pub(crate) fn new(limits: GuestLimits) -> Self {
+        Self {
+            limits,
+            memory_bytes: 0,
+            last_growth: 0,
+        }
+    }
+}
+
+impl ResourceLimiter for GuestLimiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        let total = self.memory_bytes - current + desired;
+        if let Some(limit) = self.limits.max_memory_bytes
+            && total > limit
+        {
+            // Returning an error rather than `false` makes this a trap instead
+            // of `memory.grow` returning -1, which most guests just turn into
+            // an unhelpful abort anyway.
+            return Err(LimitExceeded {
+                resource: "memory",
+                limit,
+                desired: total,
+            }
+            .into());
+        }
+        self.memory_bytes = total;
+        self.last_growth = desired - current;
+        Ok(true)
+    }
+
+    // E.g. `desired` is over the memory's own maximum, or the host is out of
+    // memory. Growth is done one memory at a time, so the failure is always
+    // the last one allowed. Returning `Ok` leaves `memory.grow` to return -1.
+    fn memory_grow_failed(&mut self, _error: anyhow::Error) -> anyhow::Result<()> {
+        self.memory_bytes -= std::mem::take(&mut self.last_growth);
+        Ok(())
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        if let Some(limit) = self.limits.max_table_elements
+            && desired > limit
+        {
+            return Err(LimitExceeded {
+                resource: "table element",
+                limit,
+                desired,
+            }
+            .into());
+        }
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits
+            .max_instances
+            .unwrap_or(wasmtime::DEFAULT_INSTANCE_LIMIT)
+    }
+}
@@ -1,83 +1,43 @@
-mod wasi_linker_excluding_filesystem;
-mod wasi_state;
+use anyhow::Result;
+use wasmtime_fs_demo::{RunOutcome, Runner};
 
-use std::path::Path;
-
-use anyhow::{Context, Result, anyhow, bail};
-use wasi_state::{GitFs, WasiState};
-use wasmtime::{
-    Engine, Store,
-    component::{Component, Linker},
-};
-use wasmtime_wasi::{I32Exit, ResourceTable, WasiCtxBuilder, p2::bindings::Command};
-
-async fn run(wasi_component_path: &Path) -> Result<()> {
-    let engine =
-        Engine::new(wasmtime::Config::new().async_support(true)).context("creating WASM engine")?;
-
-    let component = Component::from_file(&engine, wasi_component_path)?;
-
-    let mut linker = Linker::new(&engine);
-
-    // Normally we would do
-    //
-    //   wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+#[tokio::main(flavor = "multi_thread")]
+async fn main() -> Result<()> {
+    // Usage: wasmtime_fs_demo [REPO [REV]]
     //
-    // But that adds the filesystem API too and we want to use our own one. So
-    // instead we copy & paste it, removing the filesystem API ...
-    wasi_linker_excluding_filesystem::add_to_linker_async(&mut linker)?;
-
-    // ... and then add our custom one instead.
-    wasi_state::add_to_linker_async(&mut linker)?;
-
-    let wasi = WasiCtxBuilder::new()
-        .allow_tcp(false)
-        .allow_udp(false)
-        .allow_ip_name_lookup(false)
-        .inherit_stdout()
-        .inherit_stderr()
-        .build();
-
-    let repo = gix::open(Path::new(".")).context("opening repo")?;
-    let root = repo.head_tree_id().context("finding HEAD tree")?.detach();
-
-    let state = WasiState {
-        wasi_ctx: wasi,
-        resource_table: ResourceTable::new(),
-        gitfs: GitFs {
-            repo,
-            root,
-            blob_contents: Default::default(),
-            parent: Default::default(),
-        },
-    };
-
-    let mut store = Store::new(&engine, state);
-
-    let command = Command::instantiate_async(&mut store, &component, &linker).await?;
-
-    let run_result = command.wasi_cli_run().call_run(&mut store).await;
-
-    // The return type here is very weird. See
-    // https://github.com/bytecodealliance/wasmtime/issues/10767
-    match run_result {
-        Ok(res) => res.map_err(|_| anyhow!("Unknown error running WASM component"))?,
-        Err(error) => {
-            if let Some(exit) = error.downcast_ref::<I32Exit>() {
-                // Err(I32Exit(0)) is actually success.
-                if exit.0 != 0 {
-                    bail!("WASM failed with exit code {:?}", exit.0);
-                }
-            } else {
-                bail!(error);
-            }
+    // REPO can be a normal checkout or a bare repository.
+    let mut args = std::env::args().skip(1);
+    let repo = args.next().unwrap_or_else(|| ".".to_string());
+    let rev = args.next().unwrap_or_else(|| "HEAD".to_string());
+
+    let mut runner = Runner::builder()
+        .repo(repo)
+        .rev(rev)
+        .component("wasi_ls.wasm");
+    if let Some(dir) = std::env::var_os("COMPONENT_CACHE_DIR") {
+        runner = runner.component_cache(dir);
+    }
+
+    let output = runner.run().await?;
+
+    // Exit with the component's own exit code so this can be used as a
+    // drop-in replacement for running the tool natively.
+    let exit_code = match output.outcome {
+        RunOutcome::Exited(0) => return Ok(()),
+        RunOutcome::Exited(code) => code,
+        // Same as the `timeout` command.
+        RunOutcome::TimedOut => {
+            eprintln!("WASM timed out");
+            124
+        }
+        RunOutcome::OutOfFuel => {
+            eprintln!("WASM ran out of fuel");
+            124
+        }
+        RunOutcome::Trapped(error) => {
+            eprintln!("WASM trapped: {error:?}");
+            1
         }
     };
-
-    Ok(())
-}
-
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<()> {
-    run(Path::new("wasi_ls.wasm")).await
+    std::process::exit(exit_code);
 }
@@ -0,0 +1,310 @@
+//! The writable layer of a run's filesystem, holding whatever the guest has
+//! changed. The Git tree underneath is never modified.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use gix::{
+    ObjectId, Repository,
+    bstr::{BStr, BString, ByteSlice, ByteVec},
+    objs::{self, tree::EntryKind},
+};
+
+use crate::{
+    changes::{ChangedFile, Changes, FileMode},
+    shared_repo::{ObjectCache, SharedRepo},
+};
+
+// What a path has been changed to.
+#[derive(Clone)]
+pub(crate) enum Node {
+    // An object from the repository that was moved here by a rename.
+    Git { kind: EntryKind, id: ObjectId },
+    // A file the guest created or opened for writing.
+    File(WrittenFile),
+    // A directory the guest created. Only what's in the overlay is in it.
+    Dir,
+    // Deleted or moved away.
+    Removed,
+}
+
+// Changes by path relative to the root. Nothing below a path is in the
+// overlay unless that path is a directory, so removing or replacing a
+// directory removes everything that was below it too.
+#[derive(Default)]
+pub(crate) struct Overlay {
+    nodes: BTreeMap<BString, Node>,
+}
+
+impl Overlay {
+    pub(crate) fn get(&self, path: &BStr) -> Option<&Node> {
+        self.nodes.get(path)
+    }
+
+    // Change what's at `path`, forgetting anything that was below it.
+    pub(crate) fn set(&mut self, path: BString, node: Node) {
+        let below = self.below(path.as_ref());
+        for child in below {
+            self.nodes.remove(&child);
+        }
+        self.nodes.insert(path, node);
+    }
+
+    // Move everything below `from` to below `to`, for renaming a directory.
+    pub(crate) fn move_below(&mut self, from: &BStr, to: &BStr) {
+        for old in self.below(from) {
+            let node = self.nodes.remove(&old).expect("listed above");
+            let mut new = BString::from(to);
+            new.push_str(&old[from.len()..]);
+            self.nodes.insert(new, node);
+        }
+    }
+
+    // The names and nodes directly inside the directory `dir`.
+    pub(crate) fn children<'a>(
+        &'a self,
+        dir: &'a BStr,
+    ) -> impl Iterator<Item = (&'a BStr, &'a Node)> + 'a {
+        self.range_below(dir).filter_map(move |(path, node)| {
+            let name = &path[prefix_len(dir)..];
+            (!name.contains(&b'/')).then_some((name.as_bstr(), node))
+        })
+    }
+
+    // Whether anything below `dir` has been changed.
+    pub(crate) fn has_changes_below(&self, dir: &BStr) -> bool {
+        self.range_below(dir).next().is_some()
+    }
+
+    fn below(&self, dir: &BStr) -> Vec<BString> {
+        self.range_below(dir)
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    fn range_below<'a>(&'a self, dir: &'a BStr) -> impl Iterator<Item = (&'a BString, &'a Node)> {
+        let prefix = dir_prefix(dir);
+        self.nodes
+            .range(prefix.clone()..)
+            .take_while(move |(path, _)| path.starts_with(&prefix))
+    }
+
+    // The files that differ between the tree `root` and what the guest sees
+    // now, sorted by path. Submodules are left out since they can't be
+    // changed.
+    pub(crate) fn changes(&self, repo: &SharedRepo, root: ObjectId) -> Result<Changes> {
+        let local = repo.to_thread_local();
+        let mut files = Vec::new();
+        let mut differ = Differ {
+            overlay: self,
+            repo: &local,
+            objects: repo.objects(),
+            changes: &mut files,
+        };
+        differ.diff_dir(BStr::new(""), Some(root), Some(root))?;
+        Ok(Changes::new(repo.clone(), files))
+    }
+}
+
+// `dir` with a trailing slash, or nothing for the root, so that everything
+// below `dir` starts with it.
+pub(crate) fn dir_prefix(dir: &BStr) -> BString {
+    let mut prefix = BString::from(dir);
+    if !prefix.is_empty() {
+        prefix.push_byte(b'/');
+    }
+    prefix
+}
+
+fn prefix_len(dir: &BStr) -> usize {
+    if dir.is_empty() { 0 } else { dir.len() + 1 }
+}
+
+// A file's contents, shared by the overlay and every descriptor and stream
+// open on it, so that they all see each other's writes like a real file.
+#[derive(Clone)]
+pub(crate) struct WrittenFile(Arc<Mutex<FileContents>>);
+
+pub(crate) struct FileContents {
+    pub(crate) data: Vec<u8>,
+    pub(crate) executable: bool,
+}
+
+impl WrittenFile {
+    pub(crate) fn new(data: Vec<u8>, executable: bool) -> Self {
+        Self(Arc::new(Mutex::new(FileContents { data, executable })))
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, FileContents> {
+        self.0.lock().unwrap()
+    }
+
+    pub(crate) fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    // An address that identifies the file while it's open.
+    pub(crate) fn address(&self) -> u64 {
+        Arc::as_ptr(&self.0) as usize as u64
+    }
+}
+
+impl FileContents {
+    // How many bytes writing `len` bytes at `offset` would add, counting any
+    // gap filled with zeros, which is what counts against the write quota.
+    pub(crate) fn write_cost(&self, offset: usize, len: usize) -> u64 {
+        (offset.saturating_sub(self.data.len()) + len) as u64
+    }
+
+    pub(crate) fn write_at(&mut self, offset: usize, bytes: &[u8]) {
+        let end = offset + bytes.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[offset..end].copy_from_slice(bytes);
+    }
+}
+
+// A file on one side of a diff.
+enum Side {
+    Git(EntryKind, ObjectId),
+    Written(WrittenFile),
+}
+
+struct Differ<'a> {
+    overlay: &'a Overlay,
+    repo: &'a Repository,
+    objects: &'a ObjectCache,
+    changes: &'a mut Vec<ChangedFile>,
+}
+
+impl Differ<'_> {
+    // Compare the directory at `path`, which was the tree `old` and is now
+    // the tree `new` plus whatever is in the overlay below `path`. Either can
+    // be `None` if it isn't a directory.
+    fn diff_dir(
+        &mut self,
+        path: &BStr,
+        old: Option<ObjectId>,
+        new: Option<ObjectId>,
+    ) -> Result<()> {
+        let old_entries = self.entries(old)?;
+        let new_entries = self.entries(new)?;
+        let mut names: BTreeSet<&BStr> = BTreeSet::new();
+        names.extend(old_entries.keys().map(|name| name.as_bstr()));
+        names.extend(new_entries.keys().map(|name| name.as_bstr()));
+        if new.is_some() {
+            names.extend(self.overlay.children(path).map(|(name, _)| name));
+        }
+
+        for name in names {
+            let child = join(path, name);
+            let old = old_entries.get(name).copied();
+            let new = match self.overlay.get(child.as_ref()) {
+                Some(Node::Removed) => None,
+                Some(Node::Git { kind, id }) => Some(Side::Git(*kind, *id)),
+                Some(Node::File(file)) => Some(Side::Written(file.clone())),
+                Some(Node::Dir) => Some(Side::Git(
+                    EntryKind::Tree,
+                    ObjectId::empty_tree(self.repo.object_hash()),
+                )),
+                None => new_entries
+                    .get(name)
+                    .map(|(kind, id)| Side::Git(*kind, *id)),
+            };
+
+            let old_tree = old
+                .filter(|(kind, _)| *kind == EntryKind::Tree)
+                .map(|(_, id)| id);
+            let new_tree = match &new {
+                Some(Side::Git(EntryKind::Tree, id)) => Some(*id),
+                _ => None,
+            };
+            if (old_tree.is_some() || new_tree.is_some())
+                && (old_tree != new_tree || self.overlay.has_changes_below(child.as_ref()))
+            {
+                self.diff_dir(child.as_ref(), old_tree, new_tree)?;
+            }
+
+            let old = old.and_then(|(kind, id)| Some((file_mode(kind)?, id)));
+            let new = match new {
+                Some(Side::Git(kind, id)) => file_mode(kind).map(|mode| (mode, id, None)),
+                Some(Side::Written(file)) => {
+                    let file = file.lock();
+                    let mode = if file.executable {
+                        FileMode::Executable
+                    } else {
+                        FileMode::Regular
+                    };
+                    let id =
+                        objs::compute_hash(self.repo.object_hash(), objs::Kind::Blob, &file.data)?;
+                    Some((mode, id, Some(Bytes::copy_from_slice(&file.data))))
+                }
+                None => None,
+            };
+            let unchanged = match (&old, &new) {
+                (None, None) => true,
+                (Some((old_mode, old_id)), Some((new_mode, new_id, _))) => {
+                    old_mode == new_mode && old_id == new_id
+                }
+                _ => false,
+            };
+            if unchanged {
+                continue;
+            }
+            // Blobs are only loaded for files that changed, so that unchanged
+            // ones aren't read (or found to be missing in a partial clone).
+            let new = match new {
+                Some((mode, _, Some(data))) => Some((mode, data)),
+                Some((mode, id, None)) => Some((mode, self.blob(id)?)),
+                None => None,
+            };
+            self.changes.push(ChangedFile {
+                path: child,
+                old,
+                new,
+            });
+        }
+        Ok(())
+    }
+
+    fn entries(&self, tree: Option<ObjectId>) -> Result<BTreeMap<BString, (EntryKind, ObjectId)>> {
+        let Some(id) = tree else {
+            return Ok(BTreeMap::new());
+        };
+        let tree = self
+            .objects
+            .tree(self.repo, id)
+            .map_err(|error| anyhow!("reading tree {id}: {error}"))?;
+        Ok(tree
+            .iter()
+            .map(|entry| (entry.name.clone(), (entry.kind, entry.id)))
+            .collect())
+    }
+
+    fn blob(&self, id: ObjectId) -> Result<Bytes> {
+        self.objects
+            .blob(self.repo, id)
+            .map_err(|error| anyhow!("reading blob {id}: {error}"))
+    }
+}
+
+// Directories and submodules aren't files.
+fn file_mode(kind: EntryKind) -> Option<FileMode> {
+    match kind {
+        EntryKind::Blob => Some(FileMode::Regular),
+        EntryKind::BlobExecutable => Some(FileMode::Executable),
+        EntryKind::Link => Some(FileMode::Symlink),
+        EntryKind::Tree | EntryKind::Commit => None,
+    }
+}
+
+fn join(dir: &BStr, name: &BStr) -> BString {
+    let mut path = dir_prefix(dir);
+    path.push_str(name);
+    path
+}
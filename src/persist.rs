@@ -0,0 +1,130 @@
+//! Saving what a run changed, once it has finished successfully.
+
+use std::path::PathBuf;
+
+use anyhow::{Context as _, Result, bail};
+use gix::{
+    ObjectId, Repository,
+    bstr::{BString, ByteVec as _},
+    objs::tree::EntryKind,
+};
+
+use crate::{
+    changes::{Changes, FileMode},
+    shared_repo::SharedRepo,
+};
+
+/// Where [`RunnerBuilder::persist()`](crate::RunnerBuilder::persist) saves a
+/// run's changes.
+#[derive(Clone, Debug)]
+pub enum Persist {
+    /// Apply them to a directory on the host, which should hold the files the
+    /// run started with. See [`Changes::write_to_directory()`].
+    Directory(PathBuf),
+    /// Commit them on top of the revision and point the branch `name` at the
+    /// commit. The author and committer come from the repository's config.
+    ///
+    /// The branch is only updated if it doesn't exist or still points at the
+    /// revision, so a run can't overwrite commits made since the runner was
+    /// built. Nothing is committed if there are no changes.
+    Branch { name: String, message: String },
+}
+
+// A `Persist` resolved against the runner's tree when it is built.
+#[derive(Clone, Debug)]
+pub(crate) enum Target {
+    Directory(PathBuf),
+    Branch {
+        // The full ref name.
+        name: String,
+        message: String,
+        // The commit the revision resolved to, and its tree.
+        parent: ObjectId,
+        tree: ObjectId,
+        // Where the mount root is in `tree`, with a trailing slash, or empty.
+        prefix: BString,
+    },
+}
+
+impl Target {
+    // `rev` is `None` if the tree didn't come from a revision.
+    pub(crate) fn new(
+        persist: Persist,
+        repo: &Repository,
+        rev: Option<&str>,
+        subdir: Option<&str>,
+    ) -> Result<Self> {
+        Ok(match persist {
+            Persist::Directory(path) => Target::Directory(path),
+            Persist::Branch { name, message } => {
+                let Some(rev) = rev else {
+                    bail!("persisting to a branch only works with rev()");
+                };
+                let commit = repo
+                    .rev_parse_single(rev)
+                    .with_context(|| format!("resolving {rev:?}"))?
+                    .object()?
+                    .peel_to_commit()
+                    .with_context(|| format!("{rev:?} is not a commit"))?;
+                let mut prefix = BString::default();
+                for name in subdir
+                    .unwrap_or("")
+                    .split('/')
+                    .filter(|name| !name.is_empty())
+                {
+                    prefix.push_str(name);
+                    prefix.push(b'/');
+                }
+                Target::Branch {
+                    name: format!("refs/heads/{name}"),
+                    message,
+                    parent: commit.id,
+                    tree: commit.tree_id()?.detach(),
+                    prefix,
+                }
+            }
+        })
+    }
+
+    pub(crate) fn persist(&self, repo: &SharedRepo, changes: &Changes) -> Result<()> {
+        match self {
+            Target::Directory(path) => changes.write_to_directory(path),
+            Target::Branch {
+                name,
+                message,
+                parent,
+                tree,
+                prefix,
+            } => {
+                if changes.is_empty() {
+                    return Ok(());
+                }
+                let repo = repo.to_thread_local();
+                let mut editor = repo.edit_tree(*tree)?;
+                for file in changes.files() {
+                    let mut path = prefix.clone();
+                    path.push_str(&file.path);
+                    match &file.new {
+                        Some((mode, data)) => {
+                            let kind = match mode {
+                                FileMode::Regular => EntryKind::Blob,
+                                FileMode::Executable => EntryKind::BlobExecutable,
+                                FileMode::Symlink => EntryKind::Link,
+                            };
+                            editor.upsert(&path, kind, repo.write_blob(data)?)?;
+                        }
+                        None => {
+                            editor.remove(&path)?;
+                        }
+                    }
+                }
+                let new_tree = editor.write()?;
+                // This fails rather than overwriting the branch if it has
+                // moved.
+                repo.commit(name.as_str(), message, new_tree, [*parent])
+                    .with_context(|| format!("committing to {name}"))?;
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Support for legacy `wasm32-wasip1` core modules.
+//!
+//! Rather than implementing `wasi_snapshot_preview1` a second time on top of
+//! `GitFs`, we wrap the module with wasmtime's preview1 adapter, which turns it
+//! into a WASIp2 component. The adapter translates the preview1 calls into
+//! `wasi:filesystem` etc. so they go through exactly the same host
+//! implementation as a native component.
+
+use anyhow::Result;
+use wasi_preview1_component_adapter_provider::{
+    WASI_SNAPSHOT_PREVIEW1_ADAPTER_NAME, WASI_SNAPSHOT_PREVIEW1_COMMAND_ADAPTER,
+};
+use wit_component::ComponentEncoder;
+
+// Both start with `\0asm`. The next 4 bytes are the version, which is 1 for
+// core modules and 0x0001000d (version 0xd, layer 1) for components.
+pub fn is_core_module(wasm: &[u8]) -> bool {
+    wasm.starts_with(b"\0asm\x01\x00\x00\x00")
+}
+
+// Convert a `wasm32-wasip1` command module to a component.
+pub fn adapt_core_module(module: &[u8]) -> Result<Vec<u8>> {
+    ComponentEncoder::default()
+        .validate(true)
+        .module(module)?
+        .adapter(
+            WASI_SNAPSHOT_PREVIEW1_ADAPTER_NAME,
+            WASI_SNAPSHOT_PREVIEW1_COMMAND_ADAPTER,
+        )?
+        .encode()
+}
@@ -0,0 +1,197 @@
+//! Per-run limits on how much of the filesystem a guest can use.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use wasmtime_wasi::p2::bindings::filesystem::types::ErrorCode;
+
+/// Filesystem limits for a single run. `None` means unlimited.
+///
+/// Running out of descriptors, directory iterators or streams gives the guest
+/// `insufficient-memory`; going over a byte limit gives `quota`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FsQuota {
+    /// Maximum number of descriptors open at once, including the preopen.
+    pub max_descriptors: Option<usize>,
+    /// Maximum number of `read_directory()` iterators open at once.
+    pub max_directory_iterators: Option<usize>,
+    /// Maximum number of streams from `read_via_stream()`,
+    /// `write_via_stream()` and `append_via_stream()` open at once.
+    pub max_streams: Option<usize>,
+    /// Maximum total number of bytes returned from file reads.
+    pub max_bytes_read: Option<u64>,
+    /// Maximum total number of bytes written, including any gap filled with
+    /// zeros by writing past the end of a file, and the contents of existing
+    /// files that are copied into memory when opened for writing without
+    /// truncating them. Only matters with
+    /// [`RunnerBuilder::writable()`](crate::RunnerBuilder::writable).
+    pub max_bytes_written: Option<u64>,
+}
+
+/// How much of the quota a run has used.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FsUsage {
+    pub open_descriptors: usize,
+    pub peak_descriptors: usize,
+    pub open_directory_iterators: usize,
+    pub peak_directory_iterators: usize,
+    pub open_streams: usize,
+    pub peak_streams: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+// A byte count that is shared with streams, which live in the resource table
+// and so can't get at the `QuotaTracker` when they are read from.
+#[derive(Clone)]
+pub(crate) struct ByteBudget {
+    used: Arc<AtomicU64>,
+    limit: Option<u64>,
+}
+
+impl ByteBudget {
+    fn new(limit: Option<u64>) -> Self {
+        Self {
+            used: Default::default(),
+            limit,
+        }
+    }
+
+    // Record that `bytes` more bytes have been transferred, or fail with
+    // `Quota` (recording nothing) if that would exceed the limit.
+    pub(crate) fn charge(&self, bytes: u64) -> Result<(), ErrorCode> {
+        let previous = self.used.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(limit) = self.limit
+            && previous.saturating_add(bytes) > limit
+        {
+            self.used.fetch_sub(bytes, Ordering::Relaxed);
+            return Err(ErrorCode::Quota);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+// A count of open streams. Like `ByteBudget` it is shared with the streams,
+// because wasmtime drops them from the resource table without telling us.
+#[derive(Clone)]
+pub(crate) struct StreamBudget {
+    open: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+    limit: Option<usize>,
+}
+
+impl StreamBudget {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            open: Default::default(),
+            peak: Default::default(),
+            limit,
+        }
+    }
+
+    // Call before pushing a new stream into the resource table. The stream
+    // should hold on to the returned slot, which releases it when dropped.
+    pub(crate) fn open(&self) -> Result<StreamSlot, ErrorCode> {
+        let previous = self.open.fetch_add(1, Ordering::Relaxed);
+        if self.limit.is_some_and(|limit| previous >= limit) {
+            self.open.fetch_sub(1, Ordering::Relaxed);
+            return Err(ErrorCode::InsufficientMemory);
+        }
+        self.peak.fetch_max(previous + 1, Ordering::Relaxed);
+        Ok(StreamSlot {
+            open: self.open.clone(),
+        })
+    }
+}
+
+pub(crate) struct StreamSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct QuotaTracker {
+    quota: FsQuota,
+    descriptors: usize,
+    peak_descriptors: usize,
+    directory_iterators: usize,
+    peak_directory_iterators: usize,
+    pub(crate) streams: StreamBudget,
+    pub(crate) bytes_read: ByteBudget,
+    pub(crate) bytes_written: ByteBudget,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(quota: FsQuota) -> Self {
+        Self {
+            quota,
+            descriptors: 0,
+            peak_descriptors: 0,
+            directory_iterators: 0,
+            peak_directory_iterators: 0,
+            streams: StreamBudget::new(quota.max_streams),
+            bytes_read: ByteBudget::new(quota.max_bytes_read),
+            bytes_written: ByteBudget::new(quota.max_bytes_written),
+        }
+    }
+
+    // Call before pushing a new descriptor into the resource table.
+    pub(crate) fn open_descriptor(&mut self) -> Result<(), ErrorCode> {
+        open(
+            &mut self.descriptors,
+            &mut self.peak_descriptors,
+            self.quota.max_descriptors,
+        )
+    }
+
+    pub(crate) fn close_descriptor(&mut self) {
+        self.descriptors = self.descriptors.saturating_sub(1);
+    }
+
+    // Call before pushing a new directory iterator into the resource table.
+    pub(crate) fn open_directory_iterator(&mut self) -> Result<(), ErrorCode> {
+        open(
+            &mut self.directory_iterators,
+            &mut self.peak_directory_iterators,
+            self.quota.max_directory_iterators,
+        )
+    }
+
+    pub(crate) fn close_directory_iterator(&mut self) {
+        self.directory_iterators = self.directory_iterators.saturating_sub(1);
+    }
+
+    pub(crate) fn usage(&self) -> FsUsage {
+        FsUsage {
+            open_descriptors: self.descriptors,
+            peak_descriptors: self.peak_descriptors,
+            open_directory_iterators: self.directory_iterators,
+            peak_directory_iterators: self.peak_directory_iterators,
+            open_streams: self.streams.open.load(Ordering::Relaxed),
+            peak_streams: self.streams.peak.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.used(),
+            bytes_written: self.bytes_written.used(),
+        }
+    }
+}
+
+// Running out of handles is reported like running out of memory, which is
+// what a real OS would do (more or less).
+fn open(count: &mut usize, peak: &mut usize, max: Option<usize>) -> Result<(), ErrorCode> {
+    if max.is_some_and(|max| *count >= max) {
+        return Err(ErrorCode::InsufficientMemory);
+    }
+    *count += 1;
+    *peak = (*peak).max(*count);
+    Ok(())
+}
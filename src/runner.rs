@@ -0,0 +1,870 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use gix::{ObjectId, objs::tree::EntryKind, worktree::stack::state::attributes::Source};
+use wasmtime::{
+    Engine, Store, Trap, UpdateDeadline,
+    component::{Component, Linker},
+};
+use wasmtime_wasi::{
+    I32Exit, ResourceTable, WasiCtxBuilder,
+    p2::{
+        bindings::{Command, sync},
+        pipe::MemoryOutputPipe,
+    },
+};
+
+use crate::{
+    access_policy::AccessPolicy,
+    attribute_filters::AttributeFilters,
+    changes::Changes,
+    component_cache::ComponentCache,
+    git_info, index_tree,
+    limits::{GuestLimiter, GuestLimits},
+    overlay::Overlay,
+    persist::{Persist, Target},
+    preview1,
+    quota::{FsQuota, FsUsage, QuotaTracker},
+    shared_repo::{ObjectCache, SharedRepo},
+    side_by_side,
+    sparse::SparsePatterns,
+    wasi_linker_excluding_filesystem,
+    wasi_state::{self, GitFs, WasiState},
+    wasi_state_sync, worktree_tree,
+};
+
+// Where the component's stdout and stderr go.
+#[derive(Copy, Clone, Debug, Default)]
+enum OutputCapture {
+    // Write straight to the host's stdout and stderr.
+    #[default]
+    Inherit,
+    // Capture into memory, up to `max_bytes` per stream. Writing more than
+    // that traps the component.
+    Capture {
+        max_bytes: usize,
+    },
+}
+
+// Where the root tree comes from.
+#[derive(Clone, Debug)]
+enum TreeSource {
+    // A revision in any form `git rev-parse` accepts.
+    Rev(String),
+    // The index (staging area).
+    Index,
+    // Files in the working tree.
+    Worktree {
+        include_untracked: bool,
+    },
+    // Two revisions, in `a/` and `b/`.
+    SideBySide {
+        a: String,
+        b: String,
+        list_changes: bool,
+    },
+}
+
+// Settings for a single run of a component.
+#[derive(Clone, Default)]
+struct RunOptions {
+    // Which paths in the repo the component is allowed to see.
+    access_policy: AccessPolicy,
+    // Limits on descriptors and bytes read/written.
+    quota: FsQuota,
+    // Amount of fuel the component may burn. Fuel is consumed roughly once per
+    // WASM instruction. `None` means unlimited.
+    fuel: Option<u64>,
+    // Wall-clock time the component may run for. `None` means forever.
+    timeout: Option<Duration>,
+    // Limits on guest memory, tables and instances.
+    limits: GuestLimits,
+    // Whether to capture stdout and stderr.
+    output: OutputCapture,
+    // Directory to cache compiled components in. `None` to always compile.
+    component_cache: Option<PathBuf>,
+    // Command line arguments, not including the program name.
+    args: Vec<String>,
+    // Whether the guest can change files, in an overlay that is thrown away
+    // after the run.
+    writable: bool,
+}
+
+/// How the component finished.
+#[derive(Debug)]
+pub enum RunOutcome {
+    /// The component returned from `run`, or called `exit()`. Returning an
+    /// error from `run` counts as exit code 1, like wasmtime's CLI.
+    Exited(i32),
+    /// It ran for longer than the timeout.
+    TimedOut,
+    /// It used up its fuel.
+    OutOfFuel,
+    /// Any other trap, including exceeding the [`GuestLimits`].
+    Trapped(anyhow::Error),
+}
+
+/// The result of running a component. Errors from [`Runner::run()`] itself are
+/// reserved for problems on the host side (loading the component, opening the
+/// repo etc).
+#[derive(Debug)]
+pub struct RunOutput {
+    pub outcome: RunOutcome,
+    /// Captured output. `None` unless [`RunnerBuilder::capture_output()`] was
+    /// used.
+    pub stdout: Option<bytes::Bytes>,
+    pub stderr: Option<bytes::Bytes>,
+    /// How much of the filesystem quota the component used.
+    pub fs_usage: FsUsage,
+    /// What the component changed. `None` unless [`RunnerBuilder::writable()`]
+    /// was used. This is filled in however the run ended.
+    pub changes: Option<Changes>,
+    /// Whether the changes were saved by [`RunnerBuilder::persist()`], which
+    /// only happens if the component exited with 0.
+    pub persisted: bool,
+}
+
+/// Runs a WASI command component with a Git commit as its filesystem.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let output = wasmtime_fs_demo::Runner::builder()
+///     .repo(".")
+///     .rev("HEAD")
+///     .component("wasi_ls.wasm")
+///     .run()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A `Runner` is cheap to clone and can be run many times, including
+/// concurrently from different threads. All runs share one open repository
+/// and its object cache, and the compiled component. The component is compiled
+/// the first time the runner is run (once for [`run()`](Self::run) and once
+/// for [`run_sync()`](Self::run_sync)).
+#[derive(Clone)]
+pub struct Runner {
+    repo: SharedRepo,
+    // The tree of the revision (or index), resolved once when the runner is
+    // built.
+    root: ObjectId,
+    // Shared between clones so they share converted files too.
+    filters: Option<Arc<AttributeFilters>>,
+    // Whether `root` has a virtual `.gitinfo` directory.
+    git_info: bool,
+    persist: Option<Target>,
+    mount: String,
+    component: PathBuf,
+    // Compiled for async and sync runs respectively, the first time the
+    // runner is run that way. Shared between clones.
+    compiled: Arc<[Mutex<Option<Arc<Compiled>>>; 2]>,
+    options: RunOptions,
+}
+
+/// Builder for [`Runner`]. Everything except the component is optional.
+#[derive(Clone)]
+pub struct RunnerBuilder {
+    repo: PathBuf,
+    // Overrides `repo` if set.
+    shared_repo: Option<SharedRepo>,
+    tree: TreeSource,
+    git_info: bool,
+    apply_gitattributes: bool,
+    sparse: Option<SparsePatterns>,
+    subdir: Option<String>,
+    persist: Option<Persist>,
+    mount: String,
+    component: Option<PathBuf>,
+    options: RunOptions,
+}
+
+impl Runner {
+    pub fn builder() -> RunnerBuilder {
+        RunnerBuilder {
+            repo: PathBuf::from("."),
+            shared_repo: None,
+            tree: TreeSource::Rev("HEAD".to_string()),
+            git_info: false,
+            apply_gitattributes: false,
+            sparse: None,
+            subdir: None,
+            persist: None,
+            mount: "/".to_string(),
+            component: None,
+            options: RunOptions::default(),
+        }
+    }
+}
+
+impl RunnerBuilder {
+    /// Path to the Git repository. Defaults to the current directory.
+    pub fn repo(mut self, path: impl Into<PathBuf>) -> Self {
+        self.repo = path.into();
+        self
+    }
+
+    /// Use an already open repository, e.g. one shared with other runners so
+    /// they can share its object cache. Overrides [`repo()`](Self::repo).
+    pub fn shared_repo(mut self, repo: SharedRepo) -> Self {
+        self.shared_repo = Some(repo);
+        self
+    }
+
+    /// The revision to expose, in any form `git rev-parse` accepts. Defaults
+    /// to `HEAD`.
+    pub fn rev(mut self, rev: impl Into<String>) -> Self {
+        self.tree = TreeSource::Rev(rev.into());
+        self
+    }
+
+    /// Expose the index (staging area) instead of a revision, i.e. exactly
+    /// what `git commit` would commit. This is useful for pre-commit checks.
+    /// The index is read once, when the runner is built.
+    ///
+    /// Files added with `git add -N` aren't included, and building fails if
+    /// the index has unresolved merge conflicts.
+    pub fn index(mut self) -> Self {
+        self.tree = TreeSource::Index;
+        self
+    }
+
+    /// Expose the files in the working tree, including unstaged changes.
+    /// Only tracked files are visible unless `include_untracked` is set, in
+    /// which case untracked files that aren't ignored by `.gitignore` are too.
+    /// Ignored files are never visible.
+    ///
+    /// The files are read once, when the runner is built.
+    pub fn worktree(mut self, include_untracked: bool) -> Self {
+        self.tree = TreeSource::Worktree { include_untracked };
+        self
+    }
+
+    /// Expose two revisions side by side, in `a/` and `b/` under the mount
+    /// point, e.g. a merge base and a pull request's head. Files they have in
+    /// common are only loaded once.
+    ///
+    /// If `list_changes` is set there is also a `changes` file listing the
+    /// files that differ, in the same format as `git diff --name-status`.
+    pub fn side_by_side(
+        mut self,
+        a: impl Into<String>,
+        b: impl Into<String>,
+        list_changes: bool,
+    ) -> Self {
+        self.tree = TreeSource::SideBySide {
+            a: a.into(),
+            b: b.into(),
+            list_changes,
+        };
+        self
+    }
+
+    /// Leave out everything that doesn't match sparse-checkout style
+    /// patterns, as if it had never been committed.
+    pub fn sparse(mut self, patterns: SparsePatterns) -> Self {
+        self.sparse = Some(patterns);
+        self
+    }
+
+    /// Use a subdirectory of the tree, e.g. `tools/codegen`, as the mount
+    /// root instead of the whole tree. The guest can't see anything outside
+    /// it; `..` from the mount root gives `Access` as usual. Access policy
+    /// patterns are relative to the subdirectory, but sparse patterns are
+    /// still relative to the top of the repository. With
+    /// [`side_by_side()`](Self::side_by_side) it is the subdirectory of each
+    /// revision that goes in `a/` and `b/`.
+    pub fn subdir(mut self, path: impl Into<String>) -> Self {
+        self.subdir = Some(path.into());
+        self
+    }
+
+    /// Add a read-only `.gitinfo/` directory to the root, with files
+    /// describing the revision: `HEAD`, `commit`, `tree`, `author`,
+    /// `message`, and `refs` (the refs pointing at the commit). Only works
+    /// with [`rev()`](Self::rev). It stays read-only even with
+    /// [`writable()`](Self::writable).
+    pub fn git_info(mut self) -> Self {
+        self.git_info = true;
+        self
+    }
+
+    /// Convert files the way `git checkout` would according to
+    /// `.gitattributes`: line endings (`eol`, `text`), `ident`, and
+    /// `working-tree-encoding`. Sizes reported by `stat` match the converted
+    /// files. Filter drivers such as Git LFS are never run. Only works with
+    /// [`rev()`](Self::rev) and [`index()`](Self::index).
+    pub fn apply_gitattributes(mut self) -> Self {
+        self.apply_gitattributes = true;
+        self
+    }
+
+    /// Let the component create, change, rename and delete files and
+    /// directories. Changes are kept in memory for the run and never touch
+    /// the repository; [`RunOutput::changes`] says what they were, and can
+    /// export them as a patch, a tarball or files in a directory.
+    ///
+    /// Creating symlinks and hard links isn't supported, and submodules
+    /// can't be changed. Written files are held in memory, so limit
+    /// [`FsQuota::max_bytes_written`] when running untrusted components. Doesn't
+    /// work with [`apply_gitattributes()`](Self::apply_gitattributes).
+    pub fn writable(mut self) -> Self {
+        self.options.writable = true;
+        self
+    }
+
+    /// Save the changes after each run that exits with 0, all at once.
+    /// Changes from runs that exit with any other code, trap or time out are
+    /// thrown away, so a component that fails halfway never leaves half its
+    /// output behind. Implies [`writable()`](Self::writable).
+    ///
+    /// Committing to a branch only works with [`rev()`](Self::rev), and not
+    /// with [`git_info()`](Self::git_info). If saving fails, the run returns
+    /// an error.
+    pub fn persist(mut self, persist: Persist) -> Self {
+        self.persist = Some(persist);
+        self.options.writable = true;
+        self
+    }
+
+    /// Where the guest sees the revision's tree. Defaults to `/`.
+    pub fn mount(mut self, guest_path: impl Into<String>) -> Self {
+        self.mount = guest_path.into();
+        self
+    }
+
+    /// Path to the WASM component (or `wasm32-wasip1` core module) to run.
+    pub fn component(mut self, path: impl Into<PathBuf>) -> Self {
+        self.component = Some(path.into());
+        self
+    }
+
+    /// Command line arguments for the component. The program name (the
+    /// component's file name) is passed before them, as usual.
+    pub fn args<S: AsRef<str>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
+        self.options.args = args
+            .into_iter()
+            .map(|arg| arg.as_ref().to_string())
+            .collect();
+        self
+    }
+
+    /// Which paths in the repo the component is allowed to see.
+    pub fn access_policy(mut self, policy: AccessPolicy) -> Self {
+        self.options.access_policy = policy;
+        self
+    }
+
+    /// Limits on descriptors and bytes read/written.
+    pub fn quota(mut self, quota: FsQuota) -> Self {
+        self.options.quota = quota;
+        self
+    }
+
+    /// Amount of fuel the component may burn. Fuel is consumed roughly once
+    /// per WASM instruction.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.options.fuel = Some(fuel);
+        self
+    }
+
+    /// Wall-clock time the component may run for.
+    ///
+    /// [`Runner::run()`] stops the component when it runs out of time even if
+    /// it is blocked in a host call, e.g. waiting on a pollable.
+    /// [`Runner::run_sync()`] can only interrupt it while it is running WASM
+    /// code, so a component that blocks in a host call can outlast the
+    /// timeout there.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Limits on guest memory, tables and instances.
+    pub fn limits(mut self, limits: GuestLimits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Capture stdout and stderr into [`RunOutput`] instead of inheriting the
+    /// host's. Writing more than `max_bytes` to either traps the component.
+    pub fn capture_output(mut self, max_bytes: usize) -> Self {
+        self.options.output = OutputCapture::Capture { max_bytes };
+        self
+    }
+
+    /// Cache compiled components in `dir`. Cached code is loaded without
+    /// verification so `dir` must not be writable by anyone untrusted.
+    pub fn component_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.component_cache = Some(dir.into());
+        self
+    }
+
+    /// Open the repository (unless [`shared_repo()`](Self::shared_repo) was
+    /// used) and resolve the revision or read the index.
+    pub fn build(self) -> Result<Runner> {
+        let repo = match self.shared_repo {
+            Some(repo) => repo,
+            None => SharedRepo::open(&self.repo)?,
+        };
+        let local = repo.to_thread_local();
+        // Filter a tree with the sparse patterns, then narrow it to the
+        // subdirectory.
+        let narrow = |tree| {
+            let tree = match &self.sparse {
+                Some(patterns) => patterns.filter_tree(&local, repo.objects(), tree)?,
+                None => tree,
+            };
+            match &self.subdir {
+                Some(subdir) => resolve_subdir(&local, repo.objects(), tree, subdir),
+                None => Ok(tree),
+            }
+        };
+        let mut root = match &self.tree {
+            TreeSource::Rev(rev) => narrow(resolve_tree(&local, rev)?)?,
+            TreeSource::Index => narrow(index_tree::tree_from_index(&local, repo.objects())?)?,
+            TreeSource::Worktree { include_untracked } => narrow(
+                worktree_tree::tree_from_worktree(&local, repo.objects(), *include_untracked)?,
+            )?,
+            // Each side is narrowed separately so the paths match the
+            // patterns, and both sides are the subdirectory.
+            TreeSource::SideBySide { a, b, list_changes } => side_by_side::side_by_side_tree(
+                &local,
+                repo.objects(),
+                narrow(resolve_tree(&local, a)?)?,
+                narrow(resolve_tree(&local, b)?)?,
+                *list_changes,
+            )?,
+        };
+
+        if self.git_info {
+            let TreeSource::Rev(rev) = &self.tree else {
+                bail!("git_info() only works with rev()");
+            };
+            root = git_info::with_git_info(&local, repo.objects(), root, rev)?;
+        }
+
+        // Files would have to be converted back when they are written.
+        if self.apply_gitattributes && self.options.writable {
+            bail!("apply_gitattributes() doesn't work with writable()");
+        }
+
+        // The attributes come from the tree or index itself, not the working
+        // tree.
+        let subdir = self.subdir.as_deref().unwrap_or("");
+        let filters = match &self.tree {
+            _ if !self.apply_gitattributes => None,
+            TreeSource::Rev(rev) => {
+                let index = local.index_from_tree(&resolve_tree(&local, rev)?)?;
+                Some(AttributeFilters::new(
+                    &local,
+                    &index,
+                    Source::IdMapping,
+                    subdir,
+                )?)
+            }
+            TreeSource::Index => {
+                let index = local.index_or_empty()?;
+                Some(AttributeFilters::new(
+                    &local,
+                    &index,
+                    Source::IdMapping,
+                    subdir,
+                )?)
+            }
+            _ => bail!("apply_gitattributes() only works with rev() or index()"),
+        };
+
+        let rev = match &self.tree {
+            TreeSource::Rev(rev) => Some(rev.as_str()),
+            _ => None,
+        };
+        if self.git_info && matches!(self.persist, Some(Persist::Branch { .. })) {
+            bail!("persisting to a branch doesn't work with git_info()");
+        }
+        let persist = self
+            .persist
+            .map(|persist| Target::new(persist, &local, rev, self.subdir.as_deref()))
+            .transpose()?;
+
+        Ok(Runner {
+            repo,
+            root,
+            filters: filters.map(Arc::new),
+            git_info: self.git_info,
+            persist,
+            mount: self.mount,
+            component: self.component.context("no component set")?,
+            compiled: Default::default(),
+            options: self.options,
+        })
+    }
+
+    /// Shortcut for `build()` followed by [`Runner::run()`]. Building reads
+    /// trees (or the whole working tree), so it is done on tokio's blocking
+    /// pool.
+    pub async fn run(self) -> Result<RunOutput> {
+        blocking(move || self.build()).await?.run().await
+    }
+
+    /// Shortcut for `build()` followed by [`Runner::run_sync()`].
+    pub fn run_sync(self) -> Result<RunOutput> {
+        self.build()?.run_sync()
+    }
+}
+
+impl Runner {
+    pub async fn run(&self) -> Result<RunOutput> {
+        // Preparing compiles the component the first time, which takes a
+        // while.
+        let runner = self.clone();
+        let Prepared {
+            mut store,
+            compiled,
+            captured,
+        } = blocking(move || runner.prepare(true)).await?;
+
+        let run = async {
+            let command =
+                Command::instantiate_async(&mut store, &compiled.component, &compiled.linker)
+                    .await?;
+            command.wasi_cli_run().call_run(&mut store).await
+        };
+        // The epoch deadline only interrupts WASM code, so this also catches
+        // components that are stuck in host calls.
+        let run_result = match self.options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .unwrap_or_else(|_| Err(Trap::Interrupt.into())),
+            None => run.await,
+        };
+
+        let overlay = store.data_mut().gitfs.overlay.take();
+        let mut output = finish(run_result, store, captured);
+        // Diffing reads trees and blobs, and persisting writes files.
+        let runner = self.clone();
+        blocking(move || {
+            runner.save_changes(&mut output, overlay)?;
+            Ok(output)
+        })
+        .await
+    }
+
+    /// Like [`run()`](Self::run) but using a non-async `Engine`, for embedders
+    /// that don't have an async runtime.
+    pub fn run_sync(&self) -> Result<RunOutput> {
+        let Prepared {
+            mut store,
+            compiled,
+            captured,
+        } = self.prepare(false)?;
+
+        let run_result =
+            sync::Command::instantiate(&mut store, &compiled.component, &compiled.linker)
+                .and_then(|command| command.wasi_cli_run().call_run(&mut store));
+
+        let overlay = store.data_mut().gitfs.overlay.take();
+        let mut output = finish(run_result, store, captured);
+        self.save_changes(&mut output, overlay)?;
+        Ok(output)
+    }
+
+    // Work out what the component changed and, if it exited with 0, persist
+    // the changes. Otherwise they are thrown away, however far it got.
+    fn save_changes(&self, output: &mut RunOutput, overlay: Option<Overlay>) -> Result<()> {
+        let Some(overlay) = overlay else {
+            return Ok(());
+        };
+        let changes = overlay.changes(&self.repo, self.root)?;
+        if let Some(target) = &self.persist
+            && matches!(output.outcome, RunOutcome::Exited(0))
+        {
+            target.persist(&self.repo, &changes)?;
+            output.persisted = true;
+        }
+        output.changes = Some(changes);
+        Ok(())
+    }
+
+    // Get the engine, component and linker for async or sync runs, compiling
+    // the component the first time. Concurrent first runs wait for the same
+    // compilation rather than each doing their own.
+    fn compiled(&self, async_support: bool) -> Result<Arc<Compiled>> {
+        let mut compiled = self.compiled[usize::from(async_support)].lock().unwrap();
+        if let Some(compiled) = &*compiled {
+            return Ok(compiled.clone());
+        }
+        let new = Arc::new(self.compile(async_support)?);
+        *compiled = Some(new.clone());
+        Ok(new)
+    }
+
+    fn compile(&self, async_support: bool) -> Result<Compiled> {
+        let options = &self.options;
+
+        // Fuel and epoch checks make the generated code slower, so only enable
+        // them if they are needed. Async runs always need epochs so that they
+        // yield regularly.
+        let epochs = async_support || options.timeout.is_some();
+        let engine = Engine::new(
+            wasmtime::Config::new()
+                .async_support(async_support)
+                .consume_fuel(options.fuel.is_some())
+                .epoch_interruption(epochs),
+        )
+        .context("creating WASM engine")?;
+        if epochs {
+            start_epoch_ticker(&engine);
+        }
+
+        let wasm = std::fs::read(&self.component)
+            .with_context(|| format!("reading {}", self.component.display()))?;
+
+        let compile = || {
+            // Old `wasm32-wasip1` binaries are core modules, not components.
+            if preview1::is_core_module(&wasm) {
+                let adapted = preview1::adapt_core_module(&wasm)
+                    .context("adapting WASIp1 module to a component")?;
+                Component::new(&engine, adapted)
+            } else {
+                Component::new(&engine, &wasm)
+            }
+        };
+
+        let component = match &options.component_cache {
+            Some(dir) => ComponentCache::new(dir).get_or_compile(&engine, &wasm, compile)?,
+            None => compile()?,
+        };
+
+        let mut linker = Linker::new(&engine);
+
+        // Normally we would do
+        //
+        //   wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+        //
+        // But that adds the filesystem API too and we want to use our own one. So
+        // instead we copy & paste it, removing the filesystem API ...
+        //
+        // ... and then add our custom one instead.
+        if async_support {
+            wasi_linker_excluding_filesystem::add_to_linker_async(&mut linker)?;
+            wasi_state::add_to_linker_async(&mut linker)?;
+        } else {
+            wasi_linker_excluding_filesystem::add_to_linker_sync(&mut linker)?;
+            wasi_state_sync::add_to_linker_sync(&mut linker)?;
+        }
+
+        Ok(Compiled {
+            engine,
+            component,
+            linker,
+        })
+    }
+
+    // Everything up to instantiating the component, which is the same for
+    // async and sync runs apart from which bindings we use.
+    fn prepare(&self, async_support: bool) -> Result<Prepared> {
+        let compiled = self.compiled(async_support)?;
+        let options = self.options.clone();
+
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.allow_tcp(false)
+            .allow_udp(false)
+            .allow_ip_name_lookup(false);
+        if !options.args.is_empty() {
+            let program = self.component.file_name().unwrap_or_default();
+            wasi.arg(program.to_string_lossy()).args(&options.args);
+        }
+
+        // The pipes are cheap to clone and share their buffer, so we keep a copy
+        // to read the output back after the run.
+        let captured = match options.output {
+            OutputCapture::Inherit => {
+                wasi.inherit_stdout().inherit_stderr();
+                None
+            }
+            OutputCapture::Capture { max_bytes } => {
+                let stdout = MemoryOutputPipe::new(max_bytes);
+                let stderr = MemoryOutputPipe::new(max_bytes);
+                wasi.stdout(stdout.clone()).stderr(stderr.clone());
+                Some((stdout, stderr))
+            }
+        };
+
+        let wasi = wasi.build();
+
+        let state = WasiState {
+            wasi_ctx: wasi,
+            resource_table: ResourceTable::new(),
+            gitfs: GitFs {
+                repo: self.repo.to_thread_local(),
+                shared: self.repo.clone(),
+                // Sync runs don't have a tokio runtime to offload to.
+                blocking_pool: async_support,
+                root: self.root,
+                mount: self.mount.clone(),
+                policy: options.access_policy,
+                filters: self.filters.as_ref().map(AttributeFilters::for_run),
+                overlay: options.writable.then(Overlay::default),
+                read_only_dir: self.git_info.then(|| git_info::DIR_NAME.into()),
+            },
+            quota: QuotaTracker::new(options.quota),
+            limiter: GuestLimiter::new(options.limits),
+        };
+
+        let mut store = Store::new(&compiled.engine, state);
+        store.limiter(|state| &mut state.limiter);
+
+        if let Some(fuel) = options.fuel {
+            store.set_fuel(fuel)?;
+        }
+
+        if async_support {
+            // Give the runtime its worker thread back every epoch tick (and
+            // every so often when burning fuel), so that CPU-bound components
+            // don't hold up other tasks. `run()` handles the timeout.
+            store.epoch_deadline_async_yield_and_update(1);
+            if options.fuel.is_some() {
+                store.fuel_async_yield_interval(Some(FUEL_YIELD_INTERVAL))?;
+            }
+        } else if let Some(timeout) = options.timeout {
+            // The engine (and so its epoch) is shared with other runs, so each
+            // run checks its own deadline every tick.
+            let deadline = Instant::now() + timeout;
+            store.set_epoch_deadline(1);
+            store.epoch_deadline_callback(move |_| {
+                if Instant::now() >= deadline {
+                    Err(Trap::Interrupt.into())
+                } else {
+                    Ok(UpdateDeadline::Continue(1))
+                }
+            });
+        }
+
+        Ok(Prepared {
+            store,
+            compiled,
+            captured,
+        })
+    }
+}
+
+// Run `f` on tokio's blocking pool, so that it doesn't hold up the runtime's
+// worker threads.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+// Find the tree of a revision.
+fn resolve_tree(repo: &gix::Repository, rev: &str) -> Result<ObjectId> {
+    Ok(repo
+        .rev_parse_single(rev)
+        .with_context(|| format!("resolving {rev:?}"))?
+        .object()?
+        .peel_to_tree()
+        .with_context(|| format!("finding tree for {rev:?}"))?
+        .id)
+}
+
+// Find the directory at `path` inside the tree `root`.
+fn resolve_subdir(
+    repo: &gix::Repository,
+    objects: &ObjectCache,
+    root: ObjectId,
+    path: &str,
+) -> Result<ObjectId> {
+    let mut id = root;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        let tree = objects
+            .tree(repo, id)
+            .map_err(|error| anyhow!("reading tree {id}: {error}"))?;
+        let entry = tree
+            .iter()
+            .find(|entry| entry.name == name)
+            .with_context(|| format!("{path:?} not found"))?;
+        if entry.kind != EntryKind::Tree {
+            bail!("{path:?} is not a directory");
+        }
+        id = entry.id;
+    }
+    Ok(id)
+}
+
+// What all runs of a `Runner` in the same mode (async or sync) share.
+struct Compiled {
+    engine: Engine,
+    component: Component,
+    linker: Linker<WasiState>,
+}
+
+struct Prepared {
+    store: Store<WasiState>,
+    compiled: Arc<Compiled>,
+    captured: Option<(MemoryOutputPipe, MemoryOutputPipe)>,
+}
+
+// How often the epoch is incremented, which is how often async runs yield and
+// how precisely sync runs time out.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+// How much fuel async runs burn between yields, when fuel is enabled.
+const FUEL_YIELD_INTERVAL: u64 = 1_000_000;
+
+// Increment the engine's epoch every `EPOCH_TICK` until it is dropped. This
+// uses a plain thread rather than a tokio task so that it works for sync runs
+// too.
+fn start_epoch_ticker(engine: &Engine) {
+    let engine = engine.weak();
+    std::thread::spawn(move || {
+        while let Some(engine) = engine.upgrade() {
+            engine.increment_epoch();
+            // Don't keep the engine alive while sleeping.
+            drop(engine);
+            std::thread::sleep(EPOCH_TICK);
+        }
+    });
+}
+
+// Work out how the run went from the result of `call_run()`. The changes are
+// filled in afterwards by `save_changes()`.
+fn finish(
+    run_result: wasmtime::Result<Result<(), ()>>,
+    store: Store<WasiState>,
+    captured: Option<(MemoryOutputPipe, MemoryOutputPipe)>,
+) -> RunOutput {
+    // The return type here is very weird. See
+    // https://github.com/bytecodealliance/wasmtime/issues/10767
+    let outcome = match run_result {
+        Ok(Ok(())) => RunOutcome::Exited(0),
+        Ok(Err(())) => RunOutcome::Exited(1),
+        Err(error) => {
+            if let Some(exit) = error.downcast_ref::<I32Exit>() {
+                // Err(I32Exit(0)) is actually success.
+                RunOutcome::Exited(exit.0)
+            } else {
+                match error.downcast_ref::<Trap>() {
+                    Some(Trap::Interrupt) => RunOutcome::TimedOut,
+                    Some(Trap::OutOfFuel) => RunOutcome::OutOfFuel,
+                    _ => RunOutcome::Trapped(error),
+                }
+            }
+        }
+    };
+
+    RunOutput {
+        outcome,
+        stdout: captured.as_ref().map(|(stdout, _)| stdout.contents()),
+        stderr: captured.as_ref().map(|(_, stderr)| stderr.contents()),
+        fs_usage: store.data().quota.usage(),
+        changes: None,
+        persisted: false,
+    }
+}
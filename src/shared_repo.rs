@@ -0,0 +1,403 @@
+//! A repository handle and object caches that can be shared between many
+//! concurrent runs.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use gix::{
+    ObjectDetached, ObjectId, Repository, ThreadSafeRepository,
+    bstr::BString,
+    object::find,
+    objs::{Kind, TreeRefIter, tree::EntryKind},
+};
+use tempfile::TempDir;
+use tokio::task::JoinHandle;
+use wasmtime_wasi::p2::{FsError, FsResult, bindings::filesystem::types::ErrorCode};
+
+use crate::{
+    bundle,
+    large_blob::{self, LARGE_BLOB_SIZE, LargeBlobs},
+};
+
+/// A Git repository that is opened once and shared by every run that uses it,
+/// along with caches of the trees and blobs those runs have read. It is cheap
+/// to clone.
+#[derive(Clone)]
+pub struct SharedRepo {
+    repo: ThreadSafeRepository,
+    objects: Arc<ObjectCache>,
+    // Where a bundle was unpacked to. Declared last so it's deleted after the
+    // repository is closed.
+    _bundle_dir: Option<Arc<TempDir>>,
+}
+
+impl SharedRepo {
+    /// Open a repository (bare or not), or a `git bundle` file. Bundles are
+    /// unpacked into a temporary directory which is deleted when the last
+    /// clone of the `SharedRepo` is dropped. Incremental bundles aren't
+    /// supported.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if bundle::is_bundle(path) {
+            let (repo, dir) = bundle::open_bundle(path)
+                .with_context(|| format!("opening bundle {}", path.display()))?;
+            return Ok(Self {
+                _bundle_dir: Some(Arc::new(dir)),
+                ..Self::from(repo)
+            });
+        }
+        let repo = gix::open(path).with_context(|| format!("opening repo {}", path.display()))?;
+        Ok(Self::from(repo))
+    }
+
+    /// Fetch objects that are missing from this repository, e.g. because it
+    /// is a partial clone made with `--filter=blob:none`, from another local
+    /// repository instead. They are only read into memory; nothing is written
+    /// to this repository.
+    ///
+    /// This starts a new, empty object cache, so call it before sharing the
+    /// `SharedRepo`.
+    pub fn with_promisor(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let promisor =
+            gix::open(path).with_context(|| format!("opening promisor repo {}", path.display()))?;
+        self.objects = Arc::new(ObjectCache {
+            promisor: Some(promisor.into_sync()),
+            ..Default::default()
+        });
+        Ok(self)
+    }
+
+    // Each run gets its own `Repository` because they aren't `Sync`. They
+    // still share the underlying object database.
+    pub(crate) fn to_thread_local(&self) -> Repository {
+        self.repo.to_thread_local()
+    }
+
+    pub(crate) fn objects(&self) -> &ObjectCache {
+        &self.objects
+    }
+
+    // Run `load` on tokio's blocking pool. Finding objects means reading pack
+    // files and inflating them, which can take a while for big blobs and
+    // would otherwise stall whichever runtime worker the guest is on.
+    pub(crate) fn spawn_load<T: Send + 'static>(
+        &self,
+        load: impl FnOnce(&Repository, &ObjectCache) -> FsResult<T> + Send + 'static,
+    ) -> JoinHandle<FsResult<T>> {
+        let shared = self.clone();
+        tokio::task::spawn_blocking(move || load(&shared.to_thread_local(), shared.objects()))
+    }
+}
+
+// Flatten the result of awaiting a `spawn_load()` task.
+pub(crate) fn joined<T>(result: Result<FsResult<T>, tokio::task::JoinError>) -> FsResult<T> {
+    match result {
+        Ok(result) => result,
+        Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+        // Only happens if the runtime is shutting down.
+        Err(_) => Err(ErrorCode::Io.into()),
+    }
+}
+
+impl From<Repository> for SharedRepo {
+    fn from(repo: Repository) -> Self {
+        Self {
+            repo: repo.into_sync(),
+            objects: Default::default(),
+            _bundle_dir: None,
+        }
+    }
+}
+
+// A decoded tree entry that doesn't borrow from the object buffer.
+pub(crate) struct TreeEntry {
+    pub(crate) name: BString,
+    pub(crate) kind: EntryKind,
+    pub(crate) id: ObjectId,
+}
+
+// Total size of the trees and blobs kept that were read from the repository,
+// across all runs. Large blobs have their own chunk cache.
+const TREE_CACHE_BYTES: usize = 64 << 20;
+const BLOB_CACHE_BYTES: usize = 256 << 20;
+
+// Git objects never change so these are never invalidated. Objects read from
+// the repository are evicted oldest first once there are more than the
+// budgets above, and read again if needed. Objects that were inserted because
+// they aren't in the repository (e.g. trees built from the index) are kept
+// for as long as the `SharedRepo` is, since they couldn't be read again.
+//
+// We don't hold the lock while loading objects, so two runs that miss at the
+// same time may both decompress the same object. That's harmless and better
+// than serialising all loads.
+//
+// Objects that a tree refers to but that aren't in the repository (in a
+// partial or shallow clone) give `Io` rather than `NoEntry`, so the files
+// don't look like they have been deleted. The details go to the host's
+// stderr since there's no way to give them to the guest.
+pub(crate) struct ObjectCache {
+    trees: Mutex<ObjectMap<Arc<[TreeEntry]>>>,
+    blobs: Mutex<ObjectMap<Bytes>>,
+    // Large blobs aren't put in `blobs`. They are read in chunks instead.
+    large: LargeBlobs,
+    // Where to look for objects that are missing.
+    promisor: Option<ThreadSafeRepository>,
+}
+
+impl Default for ObjectCache {
+    fn default() -> Self {
+        Self {
+            trees: Mutex::new(ObjectMap::new(TREE_CACHE_BYTES)),
+            blobs: Mutex::new(ObjectMap::new(BLOB_CACHE_BYTES)),
+            large: Default::default(),
+            promisor: None,
+        }
+    }
+}
+
+impl ObjectCache {
+    pub(crate) fn cached_tree(&self, id: ObjectId) -> Option<Arc<[TreeEntry]>> {
+        self.trees.lock().unwrap().get(id)
+    }
+
+    pub(crate) fn cached_blob(&self, id: ObjectId) -> Option<Bytes> {
+        self.blobs.lock().unwrap().get(id)
+    }
+
+    // For trees that aren't in the repository, e.g. ones built from the index.
+    pub(crate) fn insert_tree(&self, id: ObjectId, entries: Arc<[TreeEntry]>) {
+        self.trees.lock().unwrap().pin(id, entries);
+    }
+
+    // For blobs that aren't in the repository, e.g. working tree files.
+    pub(crate) fn insert_blob(&self, id: ObjectId, data: Bytes) {
+        self.blobs.lock().unwrap().pin(id, data);
+    }
+
+    pub(crate) fn tree(&self, repo: &Repository, id: ObjectId) -> FsResult<Arc<[TreeEntry]>> {
+        if let Some(tree) = self.cached_tree(id) {
+            return Ok(tree);
+        }
+        let data = self.find(repo, id, Kind::Tree)?;
+        let entries = TreeRefIter::from_bytes(&data)
+            .map(|entry| {
+                let entry = entry.map_err(|_| ErrorCode::Io)?;
+                Ok(TreeEntry {
+                    name: entry.filename.to_owned(),
+                    kind: entry.mode.kind(),
+                    id: entry.oid.to_owned(),
+                })
+            })
+            .collect::<Result<Arc<[_]>, ErrorCode>>()?;
+        self.trees
+            .lock()
+            .unwrap()
+            .insert(id, entries.clone(), tree_size(&entries));
+        Ok(entries)
+    }
+
+    // Read a full blob (the only API Gix gives because it may be compressed
+    // or based on diffs).
+    pub(crate) fn blob(&self, repo: &Repository, id: ObjectId) -> FsResult<Bytes> {
+        if let Some(blob) = self.cached_blob(id) {
+            return Ok(blob);
+        }
+        let data = Bytes::from(self.find(repo, id, Kind::Blob)?);
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(id, data.clone(), data.len());
+        Ok(data)
+    }
+
+    // Read a blob from `offset` on. Most blobs are read whole and this is the
+    // rest of the blob, but for large ones it only goes to the end of a chunk.
+    // It is empty at the end of the blob.
+    pub(crate) fn blob_from(
+        &self,
+        repo: &Repository,
+        id: ObjectId,
+        offset: u64,
+    ) -> FsResult<Bytes> {
+        if let Some(data) = self.cached_blob_from(id, offset) {
+            return Ok(data);
+        }
+        if repo
+            .find_header(id)
+            .is_ok_and(|header| header.size() >= LARGE_BLOB_SIZE)
+            && let Some(chunk) = self.large.read_chunk(repo, id, offset)?
+        {
+            return Ok(chunk);
+        }
+        Ok(large_blob::from_offset(self.blob(repo, id)?, offset))
+    }
+
+    // Like `blob_from()` but only if it doesn't need to inflate anything.
+    pub(crate) fn cached_blob_from(&self, id: ObjectId, offset: u64) -> Option<Bytes> {
+        match self.cached_blob(id) {
+            Some(blob) => Some(large_blob::from_offset(blob, offset)),
+            None => self.large.cached_chunk(id, offset),
+        }
+    }
+
+    // Read up to `len` bytes of a blob from `offset`. For a large blob this
+    // stops at the end of the chunk containing `offset`, which is fine since
+    // reads are allowed to return less than was asked for.
+    pub(crate) fn blob_range(
+        &self,
+        repo: &Repository,
+        id: ObjectId,
+        offset: u64,
+        len: u64,
+    ) -> FsResult<Bytes> {
+        let data = self.blob_from(repo, id, offset)?;
+        let len = usize::try_from(len).unwrap_or(usize::MAX).min(data.len());
+        Ok(data.slice(..len))
+    }
+
+    // Read an object's data, from the promisor if it isn't in `repo`. Only
+    // missing objects are looked for there and reported as I/O errors. Any
+    // other error finding one (e.g. a corrupt pack) traps.
+    fn find(&self, repo: &Repository, id: ObjectId, kind: Kind) -> FsResult<Vec<u8>> {
+        let error = match repo.find_object(id) {
+            Ok(object) => return Ok(check_kind(object.detach(), kind)?),
+            Err(error @ find::existing::Error::NotFound { .. }) => error,
+            Err(error) => {
+                return Err(FsError::trap(
+                    anyhow::Error::new(error).context(format!("reading Git object {id}")),
+                ));
+            }
+        };
+        if let Some(promisor) = &self.promisor {
+            match promisor.to_thread_local().find_object(id) {
+                Ok(object) => return Ok(check_kind(object.detach(), kind)?),
+                Err(find::existing::Error::NotFound { .. }) => {}
+                Err(error) => {
+                    return Err(FsError::trap(
+                        anyhow::Error::new(error)
+                            .context(format!("reading Git object {id} from the promisor")),
+                    ));
+                }
+            }
+        }
+        eprintln!(
+            "Git object {id} is missing ({error}). The repository may be a partial or shallow clone."
+        );
+        Err(ErrorCode::Io.into())
+    }
+}
+
+fn check_kind(object: ObjectDetached, kind: Kind) -> Result<Vec<u8>, ErrorCode> {
+    if object.kind != kind {
+        eprintln!(
+            "Git object {} is a {}, not a {kind}",
+            object.id, object.kind
+        );
+        return Err(ErrorCode::Io);
+    }
+    Ok(object.data)
+}
+
+// Roughly how much memory a tree's entries take.
+fn tree_size(entries: &[TreeEntry]) -> usize {
+    entries
+        .iter()
+        .map(|entry| size_of::<TreeEntry>() + entry.name.len())
+        .sum()
+}
+
+// Objects by ID, either read from the repository and evicted oldest first
+// once they take more than `max_bytes`, or pinned and never evicted.
+struct ObjectMap<T> {
+    objects: HashMap<ObjectId, (T, usize)>,
+    order: VecDeque<ObjectId>,
+    bytes: usize,
+    max_bytes: usize,
+    pinned: HashMap<ObjectId, T>,
+}
+
+impl<T: Clone> ObjectMap<T> {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            objects: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+            pinned: HashMap::new(),
+        }
+    }
+
+    fn get(&self, id: ObjectId) -> Option<T> {
+        match self.pinned.get(&id) {
+            Some(object) => Some(object.clone()),
+            None => self.objects.get(&id).map(|(object, _)| object.clone()),
+        }
+    }
+
+    fn insert(&mut self, id: ObjectId, object: T, size: usize) {
+        if self.pinned.contains_key(&id) || self.objects.contains_key(&id) {
+            // Another load of the same object got there first.
+            return;
+        }
+        self.bytes += size;
+        self.order.push_back(id);
+        self.objects.insert(id, (object, size));
+        while self.bytes > self.max_bytes
+            && let Some(id) = self.order.pop_front()
+        {
+            if let Some((_, size)) = self.objects.remove(&id) {
+                self.bytes -= size;
+            }
+        }
+    }
+
+    fn pin(&mut self, id: ObjectId, object: T) {
+        self.pinned.insert(id, object);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> ObjectId {
+        ObjectId::from_bytes_or_panic(&[n; 20])
+    }
+
+    #[test]
+    fn object_map_evicts_oldest_first() {
+        let mut map = ObjectMap::new(10);
+        map.insert(id(1), 1, 4);
+        map.insert(id(2), 2, 4);
+        // Already there, so this does nothing.
+        map.insert(id(1), 3, 4);
+        assert_eq!(map.bytes, 8);
+        assert_eq!(map.get(id(1)), Some(1));
+
+        map.insert(id(3), 3, 4);
+        assert_eq!(map.bytes, 8);
+        assert_eq!(map.get(id(1)), None);
+        assert_eq!(map.get(id(2)), Some(2));
+        assert_eq!(map.get(id(3)), Some(3));
+    }
+
+    #[test]
+    fn pinned_objects_are_kept() {
+        let mut map = ObjectMap::new(10);
+        map.pin(id(1), 1);
+        map.insert(id(2), 2, 8);
+        map.insert(id(3), 3, 8);
+        assert_eq!(map.get(id(1)), Some(1));
+        assert_eq!(map.get(id(2)), None);
+        assert_eq!(map.get(id(3)), Some(3));
+        // Reading a pinned object from the repository doesn't add it again.
+        map.insert(id(1), 1, 8);
+        assert_eq!(map.bytes, 8);
+    }
+}
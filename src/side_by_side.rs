@@ -0,0 +1,139 @@
+//! A root tree with two revisions side by side, for tools that compare them.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use gix::{
+    ObjectId, Repository,
+    bstr::{BStr, BString, ByteVec},
+    objs::{self, tree::EntryKind},
+};
+
+use crate::{
+    index_tree,
+    shared_repo::{ObjectCache, TreeEntry},
+};
+
+// Build a tree containing `a/` and `b/`, and optionally a `changes` file
+// listing the paths that differ between them. Returns its ID.
+//
+// Both trees live in the same object cache, so anything they have in common
+// is only loaded once.
+pub(crate) fn side_by_side_tree(
+    repo: &Repository,
+    objects: &ObjectCache,
+    a: ObjectId,
+    b: ObjectId,
+    list_changes: bool,
+) -> Result<ObjectId> {
+    let hash_kind = repo.object_hash();
+    let mut entries = vec![
+        (BString::from("a"), EntryKind::Tree, a),
+        (BString::from("b"), EntryKind::Tree, b),
+    ];
+    if list_changes {
+        let mut changes = Vec::new();
+        diff_trees(repo, objects, Some(a), Some(b), BStr::new(""), &mut changes)?;
+        let id = objs::compute_hash(hash_kind, objs::Kind::Blob, &changes)?;
+        objects.insert_blob(id, Bytes::from(changes));
+        entries.push((BString::from("changes"), EntryKind::Blob, id));
+    }
+    index_tree::tree_from_entries(entries, hash_kind, objects)
+}
+
+// Append a line to `out` for every file that differs between the trees `a`
+// and `b`, in the same format as `git diff --name-status`: a status letter
+// (`A`dded, `D`eleted, `M`odified or `T`ype changed between a regular file,
+// a symlink and a submodule), a tab and the path. Either tree may be `None`,
+// meaning everything in the other one was added or deleted.
+fn diff_trees(
+    repo: &Repository,
+    objects: &ObjectCache,
+    a: Option<ObjectId>,
+    b: Option<ObjectId>,
+    prefix: &BStr,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    if a == b {
+        return Ok(());
+    }
+
+    let a = a.map(|id| load_tree(repo, objects, id)).transpose()?;
+    let b = b.map(|id| load_tree(repo, objects, id)).transpose()?;
+    // Sorted by name so the output is in a stable order.
+    let mut names: BTreeMap<&BStr, (Option<&TreeEntry>, Option<&TreeEntry>)> = BTreeMap::new();
+    for entry in a.iter().flat_map(|tree| tree.iter()) {
+        names.entry(entry.name.as_ref()).or_default().0 = Some(entry);
+    }
+    for entry in b.iter().flat_map(|tree| tree.iter()) {
+        names.entry(entry.name.as_ref()).or_default().1 = Some(entry);
+    }
+
+    for (name, (a, b)) in names {
+        let mut path = BString::from(prefix);
+        if !path.is_empty() {
+            path.push_byte(b'/');
+        }
+        path.push_str(name);
+
+        // Directories aren't listed themselves, only the files in them.
+        let a_tree = a
+            .filter(|entry| entry.kind == EntryKind::Tree)
+            .map(|entry| entry.id);
+        let b_tree = b
+            .filter(|entry| entry.kind == EntryKind::Tree)
+            .map(|entry| entry.id);
+        let a_file = a.filter(|entry| entry.kind != EntryKind::Tree);
+        let b_file = b.filter(|entry| entry.kind != EntryKind::Tree);
+
+        // Git lists a file before the files in a directory that replaced it
+        // (or that it replaced), since `name` sorts before `name/...`.
+        let status = match (a_file, b_file) {
+            (None, None) => None,
+            (Some(_), None) => Some(b'D'),
+            (None, Some(_)) => Some(b'A'),
+            (Some(a), Some(b)) if file_type(a.kind) != file_type(b.kind) => Some(b'T'),
+            // Including changes to just the executable bit.
+            (Some(a), Some(b)) if a.id != b.id || a.kind != b.kind => Some(b'M'),
+            (Some(_), Some(_)) => None,
+        };
+        if let Some(status) = status {
+            out.push(status);
+            out.push(b'\t');
+            out.extend_from_slice(&path);
+            out.push(b'\n');
+        }
+        if a_tree.is_some() || b_tree.is_some() {
+            diff_trees(repo, objects, a_tree, b_tree, path.as_ref(), out)?;
+        }
+    }
+    Ok(())
+}
+
+// Changing between these is a type change. An executable file is still a
+// regular file, so changing just the executable bit is a modification.
+#[derive(PartialEq)]
+enum FileType {
+    File,
+    Symlink,
+    Submodule,
+}
+
+fn file_type(kind: EntryKind) -> FileType {
+    match kind {
+        EntryKind::Link => FileType::Symlink,
+        EntryKind::Commit => FileType::Submodule,
+        _ => FileType::File,
+    }
+}
+
+fn load_tree(
+    repo: &Repository,
+    objects: &ObjectCache,
+    id: ObjectId,
+) -> Result<std::sync::Arc<[TreeEntry]>> {
+    objects
+        .tree(repo, id)
+        .map_err(|error| anyhow!("reading tree {id}: {error}"))
+}
@@ -0,0 +1,187 @@
+//! Sparse-checkout style patterns restricting which parts of the tree exist
+//! at all.
+
+use anyhow::{Result, anyhow};
+use gix::{
+    ObjectId, Repository,
+    bstr::{BStr, BString, ByteSlice, ByteVec},
+    glob::{Pattern, pattern::Case, wildmatch},
+    objs::tree::EntryKind,
+};
+
+use crate::{index_tree, shared_repo::ObjectCache};
+
+#[derive(Clone)]
+enum Mode {
+    // Directories, without leading or trailing slashes.
+    Cone(Vec<BString>),
+    // Patterns and whether they exclude (`!pattern`) rather than include.
+    Full(Vec<(Pattern, bool)>),
+}
+
+/// Patterns in the style of `git sparse-checkout`, restricting the tree to
+/// the parts that match. Everything else is left out entirely, as if it had
+/// never been committed, and directories left with nothing in them vanish
+/// too.
+///
+/// Unlike [`AccessPolicy`](crate::AccessPolicy) this changes the tree itself
+/// rather than what the guest may access, so there's no way to see that
+/// anything was left out.
+#[derive(Clone)]
+pub struct SparsePatterns {
+    mode: Mode,
+}
+
+impl SparsePatterns {
+    /// Cone mode, like `git sparse-checkout set --cone`: everything inside
+    /// `dirs`, plus the files (but not subdirectories) directly inside the
+    /// root and inside each of the directories leading to `dirs`.
+    pub fn cone<S: AsRef<str>>(dirs: impl IntoIterator<Item = S>) -> Self {
+        let dirs = dirs
+            .into_iter()
+            .map(|dir| BString::from(dir.as_ref().trim_matches('/')))
+            .collect();
+        Self {
+            mode: Mode::Cone(dirs),
+        }
+    }
+
+    /// Full patterns, in the format of `.git/info/sparse-checkout` in
+    /// non-cone mode. This is `.gitignore` syntax, except that matching paths
+    /// are included rather than ignored, and `!` excludes them again.
+    pub fn full(text: &str) -> Result<Self> {
+        let mut patterns = Vec::new();
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let pattern = Pattern::from_bytes(line.as_bytes())
+                .ok_or_else(|| anyhow!("invalid sparse pattern {line:?}"))?;
+            let exclude = pattern.is_negative();
+            patterns.push((pattern, exclude));
+        }
+        Ok(Self {
+            mode: Mode::Full(patterns),
+        })
+    }
+
+    /// Whether a file is included. `path` is relative to the root with no
+    /// leading slash.
+    pub fn includes_file(&self, path: &BStr) -> bool {
+        match &self.mode {
+            Mode::Cone(dirs) => {
+                let parent = match path.rfind_byte(b'/') {
+                    Some(end) => &path[..end],
+                    None => return true,
+                };
+                dirs.iter()
+                    .any(|dir| is_inside(parent, dir.as_ref()) || is_inside(dir.as_ref(), parent))
+            }
+            Mode::Full(patterns) => {
+                // Like Git, if nothing matches the file itself then the
+                // closest directory that matches decides.
+                let mut path = path;
+                let mut is_dir = false;
+                loop {
+                    if let Some(included) = full_match(patterns, path, is_dir) {
+                        return included;
+                    }
+                    match path.rfind_byte(b'/') {
+                        Some(end) => path = &path[..end],
+                        None => return false,
+                    }
+                    is_dir = true;
+                }
+            }
+        }
+    }
+
+    // Return a copy of the tree `root` with everything that isn't included
+    // left out. The new trees go in the object cache.
+    pub(crate) fn filter_tree(
+        &self,
+        repo: &Repository,
+        objects: &ObjectCache,
+        root: ObjectId,
+    ) -> Result<ObjectId> {
+        let mut entries = Vec::new();
+        self.collect(repo, objects, root, BString::default(), &mut entries)?;
+        index_tree::tree_from_entries(entries, repo.object_hash(), objects)
+    }
+
+    // Add everything included in the tree `id` at `dir` to `entries`.
+    fn collect(
+        &self,
+        repo: &Repository,
+        objects: &ObjectCache,
+        id: ObjectId,
+        dir: BString,
+        entries: &mut Vec<(BString, EntryKind, ObjectId)>,
+    ) -> Result<()> {
+        let tree = objects
+            .tree(repo, id)
+            .map_err(|error| anyhow!("reading tree {id}: {error}"))?;
+        for entry in tree.iter() {
+            let mut path = dir.clone();
+            if !path.is_empty() {
+                path.push_byte(b'/');
+            }
+            path.push_str(&entry.name);
+
+            if entry.kind != EntryKind::Tree {
+                if self.includes_file(path.as_ref()) {
+                    entries.push((path, entry.kind, entry.id));
+                }
+                continue;
+            }
+            match &self.mode {
+                Mode::Cone(dirs) => {
+                    if dirs
+                        .iter()
+                        .any(|cone| is_inside(path.as_ref(), cone.as_ref()))
+                    {
+                        // Whole cone directories can be kept as they are
+                        // without looking inside them.
+                        entries.push((path, EntryKind::Tree, entry.id));
+                    } else if dirs
+                        .iter()
+                        .any(|cone| is_inside(cone.as_ref(), path.as_ref()))
+                    {
+                        // On the way to a cone.
+                        self.collect(repo, objects, entry.id, path, entries)?;
+                    }
+                    // Otherwise nothing below it can be included.
+                }
+                Mode::Full(_) => self.collect(repo, objects, entry.id, path, entries)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether `path` is `dir` or something inside it.
+fn is_inside(path: &BStr, dir: &BStr) -> bool {
+    dir.is_empty()
+        || path == dir
+        || (path.starts_with(dir.as_bytes()) && path.get(dir.len()) == Some(&b'/'))
+}
+
+// `Some(true)` if the last pattern matching `path` includes it, `Some(false)`
+// if it excludes it, or `None` if no pattern matches.
+fn full_match(patterns: &[(Pattern, bool)], path: &BStr, is_dir: bool) -> Option<bool> {
+    let basename_start = path.rfind_byte(b'/').map(|p| p + 1);
+    patterns
+        .iter()
+        .rev()
+        .find(|(pattern, _)| {
+            pattern.matches_repo_relative_path(
+                path,
+                basename_start,
+                Some(is_dir),
+                Case::Sensitive,
+                wildmatch::Mode::NO_MATCH_SLASH_LITERAL,
+            )
+        })
+        .map(|(_, exclude)| !exclude)
+}
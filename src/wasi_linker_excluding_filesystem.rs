@@ -1,4 +1,5 @@
-//! Copy & paste of wasmtime-wasi's `add_to_linker_async` but without wasi-filesystem.
+//! Copy & paste of wasmtime-wasi's `add_to_linker_async` and `add_to_linker_sync`
+//! but without wasi-filesystem.
 
 use wasmtime::component::{HasData, Linker};
 use wasmtime_wasi::cli::{WasiCli, WasiCliView as _};
@@ -27,6 +28,26 @@ pub fn add_to_linker_with_options_async<T: WasiView>(
     Ok(())
 }
 
+pub fn add_to_linker_sync<T: WasiView>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+    let options = bindings::sync::LinkOptions::default();
+    add_to_linker_with_options_sync(linker, &options)
+}
+
+/// Similar to [`add_to_linker_sync`], but with the ability to enable unstable features.
+pub fn add_to_linker_with_options_sync<T: WasiView>(
+    linker: &mut Linker<T>,
+    options: &bindings::sync::LinkOptions,
+) -> anyhow::Result<()> {
+    add_sync_wasi_io(linker)?;
+    add_nonblocking_to_linker(linker, options)?;
+
+    let l = linker;
+    // bindings::sync::filesystem::types::add_to_linker::<T, WasiFilesystem>(l, T::filesystem)?;
+    bindings::sync::sockets::tcp::add_to_linker::<T, WasiSockets>(l, T::sockets)?;
+    bindings::sync::sockets::udp::add_to_linker::<T, WasiSockets>(l, T::sockets)?;
+    Ok(())
+}
+
 /// Shared functionality for [`add_to_linker_async`] and [`add_to_linker_sync`].
 fn add_nonblocking_to_linker<'a, T: WasiView, O>(
     linker: &mut Linker<T>,
@@ -82,3 +103,10 @@ fn add_async_io_to_linker<T: WasiView>(l: &mut Linker<T>) -> anyhow::Result<()>
     wasmtime_wasi_io::bindings::wasi::io::streams::add_to_linker::<T, HasIo>(l, |t| t.ctx().table)?;
     Ok(())
 }
+
+fn add_sync_wasi_io<T: WasiView>(l: &mut Linker<T>) -> anyhow::Result<()> {
+    wasmtime_wasi_io::bindings::wasi::io::error::add_to_linker::<T, HasIo>(l, |t| t.ctx().table)?;
+    bindings::sync::io::poll::add_to_linker::<T, HasIo>(l, |t| t.ctx().table)?;
+    bindings::sync::io::streams::add_to_linker::<T, HasIo>(l, |t| t.ctx().table)?;
+    Ok(())
+}
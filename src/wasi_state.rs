@@ -1,7 +1,13 @@
-use std::collections::{HashMap, hash_map};
+use std::sync::Arc;
 
 use anyhow::Context as _;
-use gix::{ObjectId, Repository, objs::tree::EntryKind};
+use bytes::Bytes;
+use futures::FutureExt as _;
+use gix::{
+    ObjectId, Repository,
+    bstr::{BStr, BString, ByteSlice, ByteVec},
+    objs::tree::EntryKind,
+};
 use wasmtime::component::{HasData, Linker, Resource};
 use wasmtime_wasi::{
     ResourceTable, ResourceTableError, WasiCtx, WasiCtxView, WasiView,
@@ -18,12 +24,26 @@ use wasmtime_wasi::{
     },
 };
 
-pub struct WasiState {
-    pub wasi_ctx: WasiCtx,
+use crate::{
+    access_policy::{AccessPolicy, DeniedBehaviour},
+    attribute_filters::RunFilters,
+    large_blob,
+    limits::GuestLimiter,
+    overlay::{Node, Overlay, WrittenFile, dir_prefix},
+    quota::{ByteBudget, QuotaTracker, StreamSlot},
+    shared_repo::{ObjectCache, SharedRepo, TreeEntry, joined},
+};
+
+pub(crate) struct WasiState {
+    pub(crate) wasi_ctx: WasiCtx,
     // This is basically a `Vec<any>`.
-    pub resource_table: ResourceTable,
+    pub(crate) resource_table: ResourceTable,
     // The git filesystem.
-    pub gitfs: GitFs,
+    pub(crate) gitfs: GitFs,
+    // Limits on descriptors and bytes transferred, and how much has been used.
+    pub(crate) quota: QuotaTracker,
+    // Limits on guest memory, tables and instances.
+    pub(crate) limiter: GuestLimiter,
 }
 
 impl WasiView for WasiState {
@@ -38,17 +58,44 @@ impl WasiView for WasiState {
 // A descriptor is the state associated with a file descriptor. It is stored
 // in the resource table. Normally this would hold any information you need
 // to access the underlying file/directory (e.g. a POSIX file descriptor).
-#[derive(Copy, Clone, PartialEq, Eq)]
-pub struct MyDescriptor {
+//
+// Descriptors refer to paths, so one that is open on a directory that is then
+// renamed or removed goes on seeing what was there.
+#[derive(Clone)]
+pub(crate) struct MyDescriptor {
     // What kind of Git object it is (blob, tree etc.)
-    pub kind: EntryKind,
-    // Git commit ID.
-    pub id: ObjectId,
+    pub(crate) kind: EntryKind,
+    // Git commit ID. The empty tree for new directories, and null for files
+    // in the overlay.
+    pub(crate) id: ObjectId,
+    // Path relative to the root of the filesystem, without leading or trailing
+    // slashes. Empty for the root itself. Used to apply the access policy.
+    pub(crate) path: BString,
+    // The contents, for files the guest has created or opened for writing.
+    pub(crate) file: Option<WrittenFile>,
+    // How it was opened.
+    pub(crate) flags: DescriptorFlags,
+}
+
+impl MyDescriptor {
+    fn is_same_object(&self, other: &Self) -> bool {
+        match (&self.file, &other.file) {
+            (Some(file), Some(other)) => file.is_same(other),
+            (None, None) => {
+                self.kind == other.kind && self.id == other.id && self.path == other.path
+            }
+            _ => false,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Tree
+    }
 }
 
 // Type returned by `read_dir()` that allows iterating through directory entries.
-pub struct MyReaddirIterator {
-    pub entries: Vec<DirectoryEntry>,
+pub(crate) struct MyReaddirIterator {
+    pub(crate) entries: Vec<DirectoryEntry>,
 }
 
 trait ResourceTableExt {
@@ -182,23 +229,29 @@ impl ResourceTableExt for ResourceTable {
     }
 }
 
-pub struct GitFs {
-    // Git repository.
-    pub repo: Repository,
+pub(crate) struct GitFs {
+    // Git repository. This is a thread-local handle onto `shared`.
+    pub(crate) repo: Repository,
+    // The repository and the trees and blobs that have been read from it,
+    // shared with every other run against it.
+    pub(crate) shared: SharedRepo,
+    // Load objects that aren't cached on tokio's blocking pool rather than
+    // on the calling thread. Must be false when there's no tokio runtime,
+    // i.e. for sync runs.
+    pub(crate) blocking_pool: bool,
     // Root tree object ID.
-    pub root: ObjectId,
-    // Blob contents. When we read a blob it goes into here.
-    // When we support writing we can modify them here too.
-    // There's no garbage collection currently - if you open a file, read
-    // it and then close it, it will stay here. This would be relatively easy
-    // to fix with a reference count.
-    pub blob_contents: HashMap<ObjectId, Vec<u8>>,
-    // Map from blob ID to its parent directory so we can implement `..` in
-    // path traversal. We add to this every time we open a file.
-    // There's no garbage collection currently - if you open a directory
-    // and close it this will stay here. This would be relatively easy to fix
-    // with a reference count, but it's probably not worth it in this case.
-    pub parent: HashMap<ObjectId, ObjectId>,
+    pub(crate) root: ObjectId,
+    // Where the guest sees the root, e.g. `/`.
+    pub(crate) mount: String,
+    // Which paths the guest is allowed to see.
+    pub(crate) policy: AccessPolicy,
+    // Converts files according to `.gitattributes`, if enabled.
+    pub(crate) filters: Option<RunFilters>,
+    // What the guest has changed, or `None` if the filesystem is read-only.
+    pub(crate) overlay: Option<Overlay>,
+    // A directory that stays read-only even if the rest isn't, i.e. the
+    // virtual `.gitinfo`.
+    pub(crate) read_only_dir: Option<BString>,
 }
 
 impl GitFs {
@@ -209,7 +262,7 @@ impl GitFs {
     //
     // Only relative paths are allowed. Absolute paths cause a permission error.
     // For this function the target file or directory (or symlink) must exist.
-    fn resolve_path(
+    async fn resolve_path(
         &mut self,
         from: MyDescriptor,
         relative_path: &str,
@@ -235,23 +288,29 @@ impl GitFs {
                         "." => continue,
                         ".." => {
                             // If there's no parent we're trying to .. above the root, which is not allowed by WASI.
-                            descriptor.id =
-                                *self.parent.get(&descriptor.id).ok_or(ErrorCode::Access)?;
-                            // Parent directory must be a directory.
-                            descriptor.kind = EntryKind::Tree;
+                            // This is the mount root, even if it's a subdirectory of the commit.
+                            if descriptor.path.is_empty() {
+                                return Err(ErrorCode::Access.into());
+                            }
+                            let parent_len = descriptor.path.rfind_byte(b'/').unwrap_or(0);
+                            descriptor.path.truncate(parent_len);
+                            // Trees don't know their parents, so walk down
+                            // from the root again. The trees are cached.
+                            descriptor = self.lookup(descriptor.path.clone()).await?;
                         }
                         // Named child.
                         _ => {
-                            // Open the current directory and find the child component.
-                            let tree = self
-                                .repo
-                                .find_tree(descriptor.id)
-                                .map_err(|_| ErrorCode::NoEntry)?;
-                            // Find the child object.
-                            let entry = tree.find_entry(component).ok_or(ErrorCode::NoEntry)?;
-
-                            descriptor.id = entry.id().detach();
-                            descriptor.kind = entry.kind();
+                            descriptor = self
+                                .child(&descriptor, component.as_bytes().as_bstr())
+                                .await?
+                                .ok_or(ErrorCode::NoEntry)?;
+
+                            // Checking every component means that nothing
+                            // inside a denied directory can be reached.
+                            self.policy.check(
+                                descriptor.path.as_ref(),
+                                descriptor.kind == EntryKind::Tree,
+                            )?;
                         }
                     }
                 }
@@ -272,18 +331,401 @@ impl GitFs {
         Ok(descriptor)
     }
 
-    // Read a full blob (the only API Gix gives because it may be compressed
-    // or based on diffs). It is cached.
-    fn read_blob(&mut self, id: ObjectId) -> FsResult<&[u8]> {
-        match self.blob_contents.entry(id) {
-            hash_map::Entry::Vacant(vacant_entry) => {
-                let mut blob = self.repo.find_blob(id).map_err(|_| ErrorCode::NoEntry)?;
-                let data = blob.take_data();
-                Ok(vacant_entry.insert(data))
+    // Find the directory at a root-relative `path` that is already known to
+    // exist.
+    async fn lookup(&mut self, path: BString) -> FsResult<MyDescriptor> {
+        let mut descriptor = self.root_descriptor();
+        for name in path.split_str("/").filter(|name| !name.is_empty()) {
+            descriptor = self
+                .child(&descriptor, name.as_bstr())
+                .await?
+                .ok_or(ErrorCode::NoEntry)?;
+        }
+        Ok(descriptor)
+    }
+
+    fn root_descriptor(&self) -> MyDescriptor {
+        MyDescriptor {
+            kind: EntryKind::Tree,
+            id: self.root,
+            path: BString::default(),
+            file: None,
+            flags: DescriptorFlags::READ,
+        }
+    }
+
+    // Find `name` in the directory `dir`, looking in the overlay first. This
+    // doesn't check the access policy.
+    async fn child(&mut self, dir: &MyDescriptor, name: &BStr) -> FsResult<Option<MyDescriptor>> {
+        let path = join_path(dir.path.as_ref(), name);
+        if let Some(node) = self
+            .overlay
+            .as_ref()
+            .and_then(|overlay| overlay.get(path.as_ref()))
+        {
+            let (kind, id, file) = match node {
+                Node::Git { kind, id } => (*kind, *id, None),
+                Node::File(file) => (file_kind(file), self.null_id(), Some(file.clone())),
+                Node::Dir => (
+                    EntryKind::Tree,
+                    ObjectId::empty_tree(self.repo.object_hash()),
+                    None,
+                ),
+                Node::Removed => return Ok(None),
+            };
+            return Ok(Some(MyDescriptor {
+                kind,
+                id,
+                path,
+                file,
+                flags: DescriptorFlags::READ,
+            }));
+        }
+        let tree = self.tree(dir.id).await?;
+        Ok(tree
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| MyDescriptor {
+                kind: entry.kind,
+                id: entry.id,
+                path,
+                file: None,
+                flags: DescriptorFlags::READ,
+            }))
+    }
+
+    // The names and kinds of everything in the directory `dir`, including
+    // what the access policy hides.
+    async fn entries(&mut self, dir: &MyDescriptor) -> FsResult<Vec<(BString, EntryKind)>> {
+        let tree = self.tree(dir.id).await?;
+        let Some(overlay) = &self.overlay else {
+            return Ok(tree
+                .iter()
+                .map(|entry| (entry.name.clone(), entry.kind))
+                .collect());
+        };
+        let mut entries: Vec<_> = tree
+            .iter()
+            .filter(|entry| {
+                overlay
+                    .get(join_path(dir.path.as_ref(), &entry.name).as_ref())
+                    .is_none()
+            })
+            .map(|entry| (entry.name.clone(), entry.kind))
+            .collect();
+        let tree_len = entries.len();
+        entries.extend(
+            overlay
+                .children(dir.path.as_ref())
+                .filter_map(|(name, node)| {
+                    let kind = match node {
+                        Node::Git { kind, .. } => *kind,
+                        Node::File(file) => file_kind(file),
+                        Node::Dir => EntryKind::Tree,
+                        Node::Removed => return None,
+                    };
+                    Some((BString::from(name), kind))
+                }),
+        );
+        if entries.len() > tree_len {
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        Ok(entries)
+    }
+
+    // The ID of files that are only in the overlay.
+    fn null_id(&self) -> ObjectId {
+        ObjectId::null(self.repo.object_hash())
+    }
+
+    // The overlay to make changes in, or `ReadOnly` if there isn't one.
+    fn overlay(&mut self) -> FsResult<&mut Overlay> {
+        Ok(self.overlay.as_mut().ok_or(ErrorCode::ReadOnly)?)
+    }
+
+    // Like `overlay()`, for changing `path`, which might be read-only anyway.
+    fn overlay_at(&mut self, path: &BStr) -> FsResult<&mut Overlay> {
+        if let Some(dir) = &self.read_only_dir
+            && (path == dir || path.starts_with(&dir_prefix(dir.as_ref())))
+        {
+            return Err(ErrorCode::ReadOnly.into());
+        }
+        self.overlay()
+    }
+
+    // Resolve everything but the last component of `path`, which must be a
+    // directory, and return it and the last component. This is for creating,
+    // removing and renaming things, so the last component can't be `.` or
+    // `..`.
+    async fn resolve_parent<'a>(
+        &mut self,
+        from: MyDescriptor,
+        path: &'a str,
+    ) -> FsResult<(MyDescriptor, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (if parent.is_empty() { "/" } else { parent }, name),
+            None => (".", path),
+        };
+        match name {
+            "" => return Err(ErrorCode::NoEntry.into()),
+            "." | ".." => return Err(ErrorCode::Invalid.into()),
+            _ => {}
+        }
+        let dir = self.resolve_path(from, parent, true).await?;
+        match dir.kind {
+            EntryKind::Tree => Ok((dir, name)),
+            // Submodules can't be changed.
+            EntryKind::Commit => Err(ErrorCode::NotPermitted.into()),
+            _ => Err(ErrorCode::NotDirectory.into()),
+        }
+    }
+
+    // Make `file` writable by copying its contents into the overlay, if they
+    // aren't there already. The copy counts as writing, against `budget`.
+    async fn copy_on_write(
+        &mut self,
+        file: &mut MyDescriptor,
+        truncate: bool,
+        budget: &ByteBudget,
+    ) -> FsResult<()> {
+        self.overlay_at(file.path.as_ref())?;
+        if let Some(written) = &file.file {
+            if truncate {
+                written.lock().data.clear();
+            }
+            return Ok(());
+        }
+        let data = if truncate {
+            Vec::new()
+        } else {
+            let contents = self.read_file(file).await?;
+            budget.charge(contents.len() as u64)?;
+            contents.to_vec()
+        };
+        let written = WrittenFile::new(data, file.kind == EntryKind::BlobExecutable);
+        self.overlay()?
+            .set(file.path.clone(), Node::File(written.clone()));
+        file.id = self.null_id();
+        file.file = Some(written);
+        Ok(())
+    }
+
+    // Whether the directory `dir` has anything in it.
+    async fn is_empty_dir(&mut self, dir: &MyDescriptor) -> FsResult<bool> {
+        Ok(self.entries(dir).await?.is_empty())
+    }
+
+    // Run `load` on the blocking pool if enabled, or on this thread if not.
+    async fn load<T: Send + 'static>(
+        &mut self,
+        load: impl FnOnce(&Repository, &ObjectCache) -> FsResult<T> + Send + 'static,
+    ) -> FsResult<T> {
+        if self.blocking_pool {
+            joined(self.shared.spawn_load(load).await)
+        } else {
+            load(&self.repo, self.shared.objects())
+        }
+    }
+
+    // Get a tree's entries. They are cached.
+    async fn tree(&mut self, id: ObjectId) -> FsResult<Arc<[TreeEntry]>> {
+        // Check the cache first to avoid a trip to the blocking pool.
+        if let Some(tree) = self.shared.objects().cached_tree(id) {
+            return Ok(tree);
+        }
+        self.load(move |repo, objects| objects.tree(repo, id)).await
+    }
+
+    // Read a full blob. It is cached.
+    async fn read_blob(&mut self, id: ObjectId) -> FsResult<Bytes> {
+        if let Some(blob) = self.shared.objects().cached_blob(id) {
+            return Ok(blob);
+        }
+        self.load(move |repo, objects| objects.blob(repo, id)).await
+    }
+
+    // Get a file's contents as the guest sees them, which is the blob
+    // contents after applying any `.gitattributes` filters.
+    async fn read_file(&mut self, file: &MyDescriptor) -> FsResult<Bytes> {
+        if let Some(data) = self.cached_file(file) {
+            return Ok(data);
+        }
+        let load = self.file_loader(file);
+        self.load(load).await
+    }
+
+    // Read up to `length` bytes of a file from `offset`. Without filters this
+    // only reads the chunks needed from large blobs.
+    async fn read_file_range(
+        &mut self,
+        file: &MyDescriptor,
+        offset: u64,
+        length: u64,
+    ) -> FsResult<Bytes> {
+        let id = file.id;
+        if let Some(written) = &file.file {
+            let contents = written.lock();
+            let start = contents.data.len().min(offset as usize);
+            let end = contents
+                .data
+                .len()
+                .min(start.saturating_add(length as usize));
+            return Ok(Bytes::copy_from_slice(&contents.data[start..end]));
+        }
+        if self.filters.is_some() {
+            let data = large_blob::from_offset(self.read_file(file).await?, offset);
+            return Ok(data.slice(..data.len().min(length as usize)));
+        }
+        // Like `blob_range()`, this may return less than `length`.
+        if let Some(data) = self.shared.objects().cached_blob_from(id, offset) {
+            return Ok(data.slice(..data.len().min(length as usize)));
+        }
+        self.load(move |repo, objects| objects.blob_range(repo, id, offset, length))
+            .await
+    }
+
+    // For `read_via_stream()` on files without filters, which reads them a
+    // part at a time. Files with filters have to be read whole with
+    // `start_file_load()`.
+    fn blob_reader(&self, file: &MyDescriptor) -> Option<BlobReader> {
+        if self.filters.is_some() || file.file.is_some() {
+            return None;
+        }
+        Some(BlobReader {
+            shared: self.shared.clone(),
+            blocking_pool: self.blocking_pool,
+            id: file.id,
+        })
+    }
+
+    // Like `read_file()` but for `read_via_stream()`, which can't wait for
+    // the load. Instead the stream waits for it.
+    fn start_file_load(&self, file: &MyDescriptor) -> FsResult<BlobData> {
+        let load = self.file_loader(file);
+        Ok(match self.cached_file(file) {
+            Some(data) => BlobData::Ready(data),
+            None if self.blocking_pool => BlobData::Loading(self.shared.spawn_load(load)),
+            None => BlobData::Ready(load(&self.repo, self.shared.objects())?),
+        })
+    }
+
+    fn cached_file(&self, file: &MyDescriptor) -> Option<Bytes> {
+        // Written files are always "cached". Readers get a copy, which is
+        // what they would see if they read it all now.
+        if let Some(written) = &file.file {
+            return Some(Bytes::copy_from_slice(&written.lock().data));
+        }
+        match &self.filters {
+            Some(filters) => filters.cached(self.shared.objects(), file.path.as_ref(), file.id),
+            None => self.shared.objects().cached_blob(file.id),
+        }
+    }
+
+    // Returns a function for `load()` that reads and converts a file.
+    fn file_loader(
+        &self,
+        file: &MyDescriptor,
+    ) -> impl FnOnce(&Repository, &ObjectCache) -> FsResult<Bytes> + Send + use<> {
+        let filters = self.filters.clone();
+        let id = file.id;
+        let path = file.path.clone();
+        move |repo, objects| {
+            let blob = objects.blob(repo, id)?;
+            match filters {
+                Some(filters) => filters.convert(repo, path.as_ref(), id, blob),
+                None => Ok(blob),
             }
-            hash_map::Entry::Occupied(occupied_entry) => Ok(occupied_entry.into_mut()),
         }
     }
+
+    // The size of a file as the guest sees it. Unless filters are enabled
+    // this doesn't need to read the whole blob.
+    async fn file_size(&mut self, file: &MyDescriptor) -> FsResult<u64> {
+        if let Some(written) = &file.file {
+            return Ok(written.lock().data.len() as u64);
+        }
+        if self.filters.is_some() {
+            return Ok(self.read_file(file).await?.len() as u64);
+        }
+        self.blob_size(file.id).await
+    }
+
+    async fn stat(&mut self, descriptor: &MyDescriptor) -> FsResult<DescriptorStat> {
+        Ok(DescriptorStat {
+            type_: gix_entry_kind_to_descriptor_type(descriptor.kind),
+            // Git doesn't support hard links and the normal case is 1, not 0.
+            link_count: 1,
+            // In posix for symlinks this is the size of the path. Does that apply here?
+            size: match descriptor.kind {
+                EntryKind::Blob | EntryKind::BlobExecutable => self.file_size(descriptor).await?,
+                // For symlinks this should return the size of the path, which Git
+                // conveniently stores as the blob data.
+                EntryKind::Link => self.blob_size(descriptor.id).await?,
+                // Directory or submodule.
+                EntryKind::Tree | EntryKind::Commit => 0,
+            },
+            // Git doesn't record this.
+            data_access_timestamp: None,
+            data_modification_timestamp: None,
+            status_change_timestamp: None,
+        })
+    }
+
+    // The size of a blob, without reading it if it isn't cached already.
+    async fn blob_size(&mut self, id: ObjectId) -> FsResult<u64> {
+        if let Some(blob) = self.shared.objects().cached_blob(id) {
+            return Ok(blob.len() as u64);
+        }
+        self.load(move |repo, objects| match repo.find_header(id) {
+            Ok(header) => Ok(header.size()),
+            // Probably missing, but it might be in the promisor. This logs
+            // the details if not.
+            Err(_) => Ok(objects.blob(repo, id)?.len() as u64),
+        })
+        .await
+    }
+}
+
+// Append a name to a root-relative path.
+fn join_path(dir: &BStr, name: impl AsRef<[u8]>) -> BString {
+    let mut path = BString::from(dir);
+    if !path.is_empty() {
+        path.push_byte(b'/');
+    }
+    path.push_str(name);
+    path
+}
+
+fn file_kind(file: &WrittenFile) -> EntryKind {
+    if file.lock().executable {
+        EntryKind::BlobExecutable
+    } else {
+        EntryKind::Blob
+    }
+}
+
+// The contents of a file opened for writing, or `BadDescriptor` if it wasn't.
+fn writable_file(descriptor: &MyDescriptor) -> FsResult<WrittenFile> {
+    match &descriptor.file {
+        Some(file) if descriptor.flags.contains(DescriptorFlags::WRITE) => Ok(file.clone()),
+        _ => Err(ErrorCode::BadDescriptor.into()),
+    }
+}
+
+fn metadata_hash(descriptor: &MyDescriptor) -> MetadataHashValue {
+    // Written files don't have an object ID, but their address is unique
+    // while they're open.
+    if let Some(file) = &descriptor.file {
+        return MetadataHashValue {
+            lower: file.address(),
+            upper: 0,
+        };
+    }
+    MetadataHashValue {
+        lower: u64::from_le_bytes(descriptor.id.as_bytes()[0..8].try_into().unwrap()),
+        upper: u64::from_le_bytes(descriptor.id.as_bytes()[8..16].try_into().unwrap()),
+    }
 }
 
 fn gix_entry_kind_to_descriptor_type(kind: EntryKind) -> DescriptorType {
@@ -302,22 +744,47 @@ fn gix_entry_kind_to_descriptor_type(kind: EntryKind) -> DescriptorType {
 // because Resources are unforgable (the runtime will trap bogus indexes).
 impl filesystem::preopens::Host for WasiState {
     fn get_directories(&mut self) -> anyhow::Result<Vec<(Resource<Descriptor>, String)>> {
-        // We have one hard-coded pre-open: `/`.
+        self.quota
+            .open_descriptor()
+            .context("descriptor quota too small for root preopen")?;
+
+        // We have one pre-open: the root tree.
         Ok(vec![(
             // Create a new file descriptor and add it to the resource table,
             // returning its index in the table.
             self.resource_table
-                .push_my_descriptor(MyDescriptor {
-                    kind: EntryKind::Tree,
-                    id: self.gitfs.root,
-                })
+                .push_my_descriptor(self.gitfs.root_descriptor())
                 .with_context(|| format!("failed to push root preopen"))?,
             // Path
-            "/".to_string(),
+            self.gitfs.mount.clone(),
         )])
     }
 }
 
+impl WasiState {
+    // For `write_via_stream()` (from `offset`) and `append_via_stream()`.
+    fn push_write_stream(
+        &mut self,
+        fd: Resource<Descriptor>,
+        offset: Option<u64>,
+    ) -> FsResult<Resource<Box<(dyn wasmtime_wasi::p2::OutputStream + 'static)>>> {
+        self.gitfs.overlay()?;
+        let file = writable_file(self.resource_table.get_my_descriptor(&fd).unwrap())?;
+        let offset = match offset {
+            Some(offset) => Some(usize::try_from(offset).map_err(|_| ErrorCode::FileTooLarge)?),
+            None => None,
+        };
+        let write_stream = WriteStream {
+            file,
+            offset,
+            bytes_written: self.quota.bytes_written.clone(),
+            _slot: self.quota.streams.open()?,
+        };
+        let boxed_write_stream: Box<dyn wasmtime_wasi::p2::OutputStream> = Box::new(write_stream);
+        Ok(self.resource_table.push(boxed_write_stream).unwrap())
+    }
+}
+
 // Allow performing all the usual filesystem operations on a file descriptor.
 impl filesystem::types::HostDescriptor for WasiState {
     fn read_via_stream(
@@ -325,14 +792,26 @@ impl filesystem::types::HostDescriptor for WasiState {
         fd: Resource<Descriptor>,
         offset: u64,
     ) -> FsResult<Resource<Box<(dyn wasmtime_wasi::p2::InputStream + 'static)>>> {
-        let descriptor = self.resource_table.get_mut_my_descriptor(&fd).unwrap();
-        let data = self.gitfs.read_blob(descriptor.id)?;
-        // TODO: Don't copy all the data.
+        let slot = self.quota.streams.open()?;
+        let descriptor = self.resource_table.get_my_descriptor(&fd).unwrap();
+        let reader = self.gitfs.blob_reader(descriptor);
+        let (data, data_offset) = match &reader {
+            Some(reader) => (reader.start(offset), offset as usize),
+            None => (self.gitfs.start_file_load(descriptor)?, 0),
+        };
+        if let BlobData::Failed(error) = data {
+            return Err(error);
+        }
         // TODO: Handle usize=32 bit. In fact, we probably can't actually read files
         // stored in Git that are more than 4 GB?
         let read_stream = ReadStream {
-            data: bytes::Bytes::copy_from_slice(data),
+            data,
+            data_offset,
             offset: offset as usize,
+            reader,
+            // Bytes are charged as they are read from the stream, not up front.
+            bytes_read: self.quota.bytes_read.clone(),
+            _slot: slot,
         };
         let boxed_read_stream: Box<dyn wasmtime_wasi::p2::InputStream> = Box::new(read_stream);
         // TODO: Drop from the resource table at some point somehow? Might have to use push_child?
@@ -341,17 +820,17 @@ impl filesystem::types::HostDescriptor for WasiState {
 
     fn write_via_stream(
         &mut self,
-        _fd: Resource<Descriptor>,
-        _offset: u64,
+        fd: Resource<Descriptor>,
+        offset: u64,
     ) -> FsResult<Resource<Box<(dyn wasmtime_wasi::p2::OutputStream + 'static)>>> {
-        Err(ErrorCode::ReadOnly.into())
+        self.push_write_stream(fd, Some(offset))
     }
 
     fn append_via_stream(
         &mut self,
-        _fd: Resource<Descriptor>,
+        fd: Resource<Descriptor>,
     ) -> FsResult<Resource<Box<(dyn wasmtime_wasi::p2::OutputStream + 'static)>>> {
-        Err(ErrorCode::ReadOnly.into())
+        self.push_write_stream(fd, None)
     }
 
     async fn advise(
@@ -371,8 +850,7 @@ impl filesystem::types::HostDescriptor for WasiState {
     }
 
     async fn get_flags(&mut self, fd: Resource<Descriptor>) -> FsResult<DescriptorFlags> {
-        // TODO: I guess we will need to record in the descriptor how it was opened.
-        Ok(DescriptorFlags::READ)
+        Ok(self.resource_table.get_my_descriptor(&fd).unwrap().flags)
     }
 
     async fn get_type(&mut self, fd: Resource<Descriptor>) -> FsResult<DescriptorType> {
@@ -380,8 +858,15 @@ impl filesystem::types::HostDescriptor for WasiState {
         Ok(gix_entry_kind_to_descriptor_type(descriptor.kind))
     }
 
-    async fn set_size(&mut self, _fd: Resource<Descriptor>, _size: Filesize) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+    async fn set_size(&mut self, fd: Resource<Descriptor>, size: Filesize) -> FsResult<()> {
+        self.gitfs.overlay()?;
+        let file = writable_file(self.resource_table.get_my_descriptor(&fd).unwrap())?;
+        let size = usize::try_from(size).map_err(|_| ErrorCode::FileTooLarge)?;
+        let mut contents = file.lock();
+        let growth = size.saturating_sub(contents.data.len());
+        self.quota.bytes_written.charge(growth as u64)?;
+        contents.data.resize(size, 0);
+        Ok(())
     }
 
     async fn set_times(
@@ -390,7 +875,9 @@ impl filesystem::types::HostDescriptor for WasiState {
         _data_access_timestamp: NewTimestamp,
         _data_modification_timestamp: NewTimestamp,
     ) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+        // Git doesn't record times, so there's nothing to change.
+        self.gitfs.overlay()?;
+        Ok(())
     }
 
     async fn read(
@@ -399,51 +886,61 @@ impl filesystem::types::HostDescriptor for WasiState {
         length: Filesize,
         offset: Filesize,
     ) -> FsResult<(Vec<u8>, bool)> {
-        let descriptor = self.resource_table.get_mut_my_descriptor(&fd).unwrap();
-        let blob = self.gitfs.read_blob(descriptor.id)?;
-        // TODO: Handle usize properly.
-        let length = length as usize;
-        let offset = offset as usize;
-        if offset >= blob.len() {
-            // TODO: Should this be an error?
-            Ok((Vec::new(), true))
-        } else {
-            let length = length.min(blob.len() - offset);
-            let eof = offset + length >= blob.len();
-            Ok((blob[offset..(offset + length)].to_owned(), eof))
-        }
+        let descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        let data = self
+            .gitfs
+            .read_file_range(&descriptor, offset, length)
+            .await?;
+        // TODO: Should reading past the end be an error?
+        let eof = offset + data.len() as u64 >= self.gitfs.file_size(&descriptor).await?;
+        self.quota.bytes_read.charge(data.len() as u64)?;
+        Ok((data.to_vec(), eof))
     }
 
     async fn write(
         &mut self,
-        _fd: Resource<Descriptor>,
-        _buffer: Vec<u8>,
-        _offset: Filesize,
+        fd: Resource<Descriptor>,
+        buffer: Vec<u8>,
+        offset: Filesize,
     ) -> FsResult<Filesize> {
-        Err(ErrorCode::ReadOnly.into())
+        self.gitfs.overlay()?;
+        let file = writable_file(self.resource_table.get_my_descriptor(&fd).unwrap())?;
+        let offset = usize::try_from(offset).map_err(|_| ErrorCode::FileTooLarge)?;
+        let mut contents = file.lock();
+        self.quota
+            .bytes_written
+            .charge(contents.write_cost(offset, buffer.len()))?;
+        contents.write_at(offset, &buffer);
+        Ok(buffer.len() as Filesize)
     }
 
     async fn read_directory(
         &mut self,
         fd: Resource<Descriptor>,
     ) -> FsResult<Resource<ReaddirIterator>> {
-        let descriptor = self.resource_table.get_my_descriptor(&fd).unwrap();
-        // TODO: Could use `find_tree_iter()` ideally but I don't know if the
-        // lifetime issues are easy to deal with, or if it makes any performance difference.
-        let tree = self.gitfs.repo.find_tree(descriptor.id).unwrap();
-        let mut entries: Vec<_> = tree
-            .iter()
-            .map(|entry| {
-                let entry = entry.unwrap();
-                DirectoryEntry {
-                    type_: gix_entry_kind_to_descriptor_type(entry.kind()),
-                    name: entry.filename().to_string(),
-                }
+        // Cloned so we don't hold a borrow of the resource table while waiting.
+        let descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        let entries = self.gitfs.entries(&descriptor).await?;
+        let policy = &self.gitfs.policy;
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            // Forbidden entries are still listed; they just can't be opened.
+            .filter(|(name, kind)| {
+                policy.denied == DeniedBehaviour::Forbid
+                    || !policy.is_denied(
+                        join_path(descriptor.path.as_ref(), name).as_ref(),
+                        *kind == EntryKind::Tree,
+                    )
+            })
+            .map(|(name, kind)| DirectoryEntry {
+                type_: gix_entry_kind_to_descriptor_type(kind),
+                name: name.to_string(),
             })
             .collect();
         // Reverse because we pop them off the back when reading.
         // TODO: Probably can do this more efficiently somehow.
         entries.reverse();
+        self.quota.open_directory_iterator()?;
         Ok(self
             .resource_table
             .push_my_readdiriterator(MyReaddirIterator { entries })
@@ -457,33 +954,24 @@ impl filesystem::types::HostDescriptor for WasiState {
 
     async fn create_directory_at(
         &mut self,
-        _fd: Resource<Descriptor>,
-        _path: String,
+        fd: Resource<Descriptor>,
+        path: String,
     ) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+        self.gitfs.overlay()?;
+        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        let (dir, name) = self.gitfs.resolve_parent(from_descriptor, &path).await?;
+        let path = join_path(dir.path.as_ref(), name);
+        self.gitfs.policy.check(path.as_ref(), true)?;
+        if self.gitfs.child(&dir, name.into()).await?.is_some() {
+            return Err(ErrorCode::Exist.into());
+        }
+        self.gitfs.overlay_at(path.as_ref())?.set(path, Node::Dir);
+        Ok(())
     }
 
     async fn stat(&mut self, fd: Resource<Descriptor>) -> FsResult<DescriptorStat> {
-        let descriptor = self.resource_table.get_my_descriptor(&fd).unwrap();
-        Ok(DescriptorStat {
-            type_: gix_entry_kind_to_descriptor_type(descriptor.kind),
-            // Git doesn't support hard links and the normal case is 1, not 0.
-            link_count: 1,
-            // In posix for symlinks this is the size of the path. Does that apply here?
-            size: match descriptor.kind {
-                // For symlinks this should return the size of the path, which Git
-                // conveniently stores as the blob data, so we can use the same code.
-                EntryKind::Blob | EntryKind::BlobExecutable | EntryKind::Link => {
-                    self.gitfs.repo.find_header(descriptor.id).unwrap().size()
-                }
-                // Directory or submodule.
-                EntryKind::Tree | EntryKind::Commit => 0,
-            },
-            // Git doesn't record this.
-            data_access_timestamp: None,
-            data_modification_timestamp: None,
-            status_change_timestamp: None,
-        })
+        let descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        self.gitfs.stat(&descriptor).await
     }
 
     async fn stat_at(
@@ -492,43 +980,31 @@ impl filesystem::types::HostDescriptor for WasiState {
         path_flags: PathFlags,
         path: String,
     ) -> FsResult<DescriptorStat> {
-        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap();
+        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
         let follow_final_symlink: bool = path_flags.contains(PathFlags::SYMLINK_FOLLOW);
         let descriptor = self
             .gitfs
-            .resolve_path(*from_descriptor, &path, follow_final_symlink)?;
-
-        // TODO: Extract into function.
-        Ok(DescriptorStat {
-            type_: gix_entry_kind_to_descriptor_type(descriptor.kind),
-            // Git doesn't support hard links and the normal case is 1, not 0.
-            link_count: 1,
-            // In posix for symlinks this is the size of the path. Does that apply here?
-            size: match descriptor.kind {
-                // For symlinks this should return the size of the path, which Git
-                // conveniently stores as the blob data, so we can use the same code.
-                EntryKind::Blob | EntryKind::BlobExecutable | EntryKind::Link => {
-                    self.gitfs.repo.find_header(descriptor.id).unwrap().size()
-                }
-                // Directory or submodule.
-                EntryKind::Tree | EntryKind::Commit => 0,
-            },
-            // Git doesn't record this.
-            data_access_timestamp: None,
-            data_modification_timestamp: None,
-            status_change_timestamp: None,
-        })
+            .resolve_path(from_descriptor, &path, follow_final_symlink)
+            .await?;
+        self.gitfs.stat(&descriptor).await
     }
 
     async fn set_times_at(
         &mut self,
-        _fd: Resource<Descriptor>,
-        _path_flags: PathFlags,
-        _path: String,
+        fd: Resource<Descriptor>,
+        path_flags: PathFlags,
+        path: String,
         _data_access_timestamp: NewTimestamp,
         _data_modification_timestamp: NewTimestamp,
     ) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+        // Like `set_times()`, but the path still has to exist.
+        self.gitfs.overlay()?;
+        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        let follow_final_symlink: bool = path_flags.contains(PathFlags::SYMLINK_FOLLOW);
+        self.gitfs
+            .resolve_path(from_descriptor, &path, follow_final_symlink)
+            .await?;
+        Ok(())
     }
 
     async fn link_at(
@@ -539,7 +1015,9 @@ impl filesystem::types::HostDescriptor for WasiState {
         _new_descriptor: Resource<Descriptor>,
         _new_path: String,
     ) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+        // Git doesn't have hard links.
+        self.gitfs.overlay()?;
+        Err(ErrorCode::Unsupported.into())
     }
 
     // Open the relative path `path`, relative to the directory `fd`. Unlike
@@ -552,20 +1030,65 @@ impl filesystem::types::HostDescriptor for WasiState {
         open_flags: OpenFlags,
         flags: DescriptorFlags,
     ) -> FsResult<Resource<Descriptor>> {
-        if open_flags.contains(OpenFlags::CREATE)
-            || open_flags.contains(OpenFlags::TRUNCATE)
-            || flags.contains(DescriptorFlags::WRITE)
-        {
-            return Err(ErrorCode::ReadOnly.into());
+        let create = open_flags.contains(OpenFlags::CREATE);
+        let truncate = open_flags.contains(OpenFlags::TRUNCATE);
+        let write = flags.contains(DescriptorFlags::WRITE);
+        if create || truncate || write {
+            self.gitfs.overlay()?;
         }
 
         // TODO: Handle other DescriptorFlags maybe.
 
-        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap();
+        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
         let follow_final_symlink: bool = path_flags.contains(PathFlags::SYMLINK_FOLLOW);
-        let descriptor = self
-            .gitfs
-            .resolve_path(*from_descriptor, &path, follow_final_symlink)?;
+        let existing = if create {
+            let (dir, name) = self
+                .gitfs
+                .resolve_parent(from_descriptor.clone(), &path)
+                .await?;
+            match self.gitfs.child(&dir, name.into()).await? {
+                Some(_) => Some(
+                    self.gitfs
+                        .resolve_path(dir, name, follow_final_symlink)
+                        .await?,
+                ),
+                None => {
+                    if open_flags.contains(OpenFlags::DIRECTORY) {
+                        return Err(ErrorCode::Invalid.into());
+                    }
+                    let path = join_path(dir.path.as_ref(), name);
+                    self.gitfs.policy.check(path.as_ref(), false)?;
+                    self.gitfs.overlay_at(path.as_ref())?;
+                    // Before creating the file, so it isn't left behind if
+                    // there are too many descriptors.
+                    self.quota.open_descriptor()?;
+                    let file = WrittenFile::new(Vec::new(), false);
+                    self.gitfs
+                        .overlay()?
+                        .set(path.clone(), Node::File(file.clone()));
+                    return Ok(self
+                        .resource_table
+                        .push_my_descriptor(MyDescriptor {
+                            kind: EntryKind::Blob,
+                            id: self.gitfs.null_id(),
+                            path,
+                            file: Some(file),
+                            flags,
+                        })
+                        .unwrap());
+                }
+            }
+        } else {
+            None
+        };
+        let mut descriptor = match existing {
+            Some(descriptor) => descriptor,
+            None => {
+                self.gitfs
+                    .resolve_path(from_descriptor, &path, follow_final_symlink)
+                    .await?
+            }
+        };
 
         if open_flags.contains(OpenFlags::EXCLUSIVE) {
             return Err(ErrorCode::Exist.into());
@@ -575,43 +1098,134 @@ impl filesystem::types::HostDescriptor for WasiState {
             return Err(ErrorCode::NotDirectory.into());
         }
 
+        if truncate || write {
+            match descriptor.kind {
+                EntryKind::Blob | EntryKind::BlobExecutable => {}
+                EntryKind::Tree | EntryKind::Commit => return Err(ErrorCode::IsDirectory.into()),
+                // Only reached without `SYMLINK_FOLLOW`, like `O_NOFOLLOW`.
+                EntryKind::Link => return Err(ErrorCode::Loop.into()),
+            }
+        }
+
+        // Before copying or truncating the file, so a failed open doesn't
+        // change it.
+        self.quota.open_descriptor()?;
+        if (truncate || write)
+            && let Err(error) = self
+                .gitfs
+                .copy_on_write(&mut descriptor, truncate, &self.quota.bytes_written)
+                .await
+        {
+            self.quota.close_descriptor();
+            return Err(error);
+        }
+        descriptor.flags = flags;
         Ok(self.resource_table.push_my_descriptor(descriptor).unwrap())
     }
 
     async fn readlink_at(&mut self, fd: Resource<Descriptor>, path: String) -> FsResult<String> {
-        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap();
-        let descriptor = self.gitfs.resolve_path(*from_descriptor, &path, false)?;
+        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        let descriptor = self
+            .gitfs
+            .resolve_path(from_descriptor, &path, false)
+            .await?;
 
         if descriptor.kind != EntryKind::Link {
             return Err(ErrorCode::Invalid.into());
         }
 
-        let mut link = self
-            .gitfs
-            .repo
-            .find_blob(descriptor.id)
-            .map_err(|_| ErrorCode::NoEntry)?;
-        let link_str =
-            String::from_utf8(link.take_data()).map_err(|_| ErrorCode::IllegalByteSequence)?;
+        let link = self.gitfs.read_blob(descriptor.id).await?;
+        let link_str = std::str::from_utf8(&link).map_err(|_| ErrorCode::IllegalByteSequence)?;
         Ok(link_str.to_owned())
     }
 
     async fn remove_directory_at(
         &mut self,
-        _fd: Resource<Descriptor>,
-        _path: String,
+        fd: Resource<Descriptor>,
+        path: String,
     ) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+        self.gitfs.overlay()?;
+        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        let (dir, name) = self.gitfs.resolve_parent(from_descriptor, &path).await?;
+        let target = self.gitfs.resolve_path(dir, name, false).await?;
+        match target.kind {
+            EntryKind::Tree => {}
+            // Submodules can't be changed.
+            EntryKind::Commit => return Err(ErrorCode::NotPermitted.into()),
+            _ => return Err(ErrorCode::NotDirectory.into()),
+        }
+        if !self.gitfs.is_empty_dir(&target).await? {
+            return Err(ErrorCode::NotEmpty.into());
+        }
+        self.gitfs
+            .overlay_at(target.path.as_ref())?
+            .set(target.path, Node::Removed);
+        Ok(())
     }
 
     async fn rename_at(
         &mut self,
-        _fd: Resource<Descriptor>,
-        _old_path: String,
-        _new_descriptor: Resource<Descriptor>,
-        _new_path: String,
+        fd: Resource<Descriptor>,
+        old_path: String,
+        new_descriptor: Resource<Descriptor>,
+        new_path: String,
     ) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+        self.gitfs.overlay()?;
+        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        let (dir, name) = self
+            .gitfs
+            .resolve_parent(from_descriptor, &old_path)
+            .await?;
+        let source = self.gitfs.resolve_path(dir, name, false).await?;
+        if source.kind == EntryKind::Commit {
+            return Err(ErrorCode::NotPermitted.into());
+        }
+
+        let new_from = self
+            .resource_table
+            .get_my_descriptor(&new_descriptor)
+            .unwrap()
+            .clone();
+        let (new_dir, new_name) = self.gitfs.resolve_parent(new_from, &new_path).await?;
+        let new_path = join_path(new_dir.path.as_ref(), new_name);
+        self.gitfs
+            .policy
+            .check(new_path.as_ref(), source.is_dir())?;
+        if new_path == source.path {
+            return Ok(());
+        }
+        // A directory can't be moved inside itself.
+        if source.is_dir() && new_path.starts_with(&dir_prefix(source.path.as_ref())) {
+            return Err(ErrorCode::Invalid.into());
+        }
+        if let Some(target) = self.gitfs.child(&new_dir, new_name.into()).await? {
+            match (source.is_dir(), target.kind) {
+                (_, EntryKind::Commit) => return Err(ErrorCode::NotPermitted.into()),
+                (true, EntryKind::Tree) => {
+                    if !self.gitfs.is_empty_dir(&target).await? {
+                        return Err(ErrorCode::NotEmpty.into());
+                    }
+                }
+                (true, _) => return Err(ErrorCode::NotDirectory.into()),
+                (false, EntryKind::Tree) => return Err(ErrorCode::IsDirectory.into()),
+                (false, _) => {}
+            }
+        }
+
+        let node = match source.file {
+            Some(file) => Node::File(file),
+            None => Node::Git {
+                kind: source.kind,
+                id: source.id,
+            },
+        };
+        self.gitfs.overlay_at(source.path.as_ref())?;
+        // In this order because `set()` forgets what was below the path.
+        let overlay = self.gitfs.overlay_at(new_path.as_ref())?;
+        overlay.set(new_path.clone(), node);
+        overlay.move_below(source.path.as_ref(), new_path.as_ref());
+        overlay.set(source.path, Node::Removed);
+        Ok(())
     }
 
     async fn symlink_at(
@@ -620,11 +1234,24 @@ impl filesystem::types::HostDescriptor for WasiState {
         _old_path: String,
         _new_path: String,
     ) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+        // Existing symlinks can be renamed and removed, but not created.
+        self.gitfs.overlay()?;
+        Err(ErrorCode::Unsupported.into())
     }
 
-    async fn unlink_file_at(&mut self, _fd: Resource<Descriptor>, _path: String) -> FsResult<()> {
-        Err(ErrorCode::ReadOnly.into())
+    async fn unlink_file_at(&mut self, fd: Resource<Descriptor>, path: String) -> FsResult<()> {
+        self.gitfs.overlay()?;
+        let from_descriptor = self.resource_table.get_my_descriptor(&fd).unwrap().clone();
+        let (dir, name) = self.gitfs.resolve_parent(from_descriptor, &path).await?;
+        let target = self.gitfs.resolve_path(dir, name, false).await?;
+        if matches!(target.kind, EntryKind::Tree | EntryKind::Commit) {
+            return Err(ErrorCode::IsDirectory.into());
+        }
+        // Descriptors open on the file can still use it.
+        self.gitfs
+            .overlay_at(target.path.as_ref())?
+            .set(target.path, Node::Removed);
+        Ok(())
     }
 
     async fn is_same_object(
@@ -634,17 +1261,14 @@ impl filesystem::types::HostDescriptor for WasiState {
     ) -> wasmtime::Result<bool> {
         let fd = self.resource_table.get_my_descriptor(&fd).unwrap();
         let other = self.resource_table.get_my_descriptor(&other).unwrap();
-        Ok(fd == other)
+        Ok(fd.is_same_object(other))
     }
 
     async fn metadata_hash(&mut self, fd: Resource<Descriptor>) -> FsResult<MetadataHashValue> {
         // Kind of unclear what the use case for this is if you ask me.
-        // While this is read-only we can just return the object ID which is long enough.
+        // Unless it's been written we can just return the object ID which is long enough.
         let descriptor = self.resource_table.get_my_descriptor(&fd).unwrap();
-        Ok(MetadataHashValue {
-            lower: u64::from_le_bytes(descriptor.id.as_bytes()[0..8].try_into().unwrap()),
-            upper: u64::from_le_bytes(descriptor.id.as_bytes()[8..16].try_into().unwrap()),
-        })
+        Ok(metadata_hash(descriptor))
     }
 
     async fn metadata_hash_at(
@@ -654,17 +1278,14 @@ impl filesystem::types::HostDescriptor for WasiState {
         _path: String,
     ) -> FsResult<MetadataHashValue> {
         // Kind of unclear what the use case for this is if you ask me.
-        // While this is read-only we can just return the object ID which is long enough.
         let descriptor = self.resource_table.get_my_descriptor(&fd).unwrap();
-        Ok(MetadataHashValue {
-            lower: u64::from_le_bytes(descriptor.id.as_bytes()[0..8].try_into().unwrap()),
-            upper: u64::from_le_bytes(descriptor.id.as_bytes()[8..16].try_into().unwrap()),
-        })
+        Ok(metadata_hash(descriptor))
     }
 
     fn drop(&mut self, fd: Resource<Descriptor>) -> anyhow::Result<()> {
         // This will drop the `Descriptor` which should close the file.
         self.resource_table.delete_my_descriptor(fd)?;
+        self.quota.close_descriptor();
         Ok(())
     }
 }
@@ -685,6 +1306,7 @@ impl filesystem::types::HostDirectoryEntryStream for WasiState {
 
     fn drop(&mut self, stream: Resource<ReaddirIterator>) -> anyhow::Result<()> {
         self.resource_table.delete_my_readdiriterator(stream)?;
+        self.quota.close_directory_iterator();
         Ok(())
     }
 }
@@ -700,15 +1322,81 @@ impl filesystem::types::Host for WasiState {
     ) -> anyhow::Result<Option<ErrorCode>> {
         let err = self.resource_table.get(&err)?;
 
-        // TODO: Do something here?
+        // Our streams report failures (e.g. running out of quota) as an
+        // `ErrorCode` wrapped in the stream error.
+        Ok(err.downcast_ref::<ErrorCode>().copied())
+    }
+}
 
-        Ok(None)
+// The contents of a file opened with `read_via_stream()`, or part of it.
+enum BlobData {
+    Ready(Bytes),
+    // Still being loaded on the blocking pool.
+    Loading(tokio::task::JoinHandle<FsResult<Bytes>>),
+    Failed(FsError),
+}
+
+// Loads a blob for a `ReadStream` a part at a time, so that large blobs don't
+// have to be held in memory all at once.
+struct BlobReader {
+    shared: SharedRepo,
+    blocking_pool: bool,
+    id: ObjectId,
+}
+
+impl BlobReader {
+    // Start loading the part of the blob from `offset` (see
+    // `ObjectCache::blob_from()`).
+    fn start(&self, offset: u64) -> BlobData {
+        let id = self.id;
+        if let Some(data) = self.shared.objects().cached_blob_from(id, offset) {
+            return BlobData::Ready(data);
+        }
+        let load =
+            move |repo: &Repository, objects: &ObjectCache| objects.blob_from(repo, id, offset);
+        if self.blocking_pool {
+            return BlobData::Loading(self.shared.spawn_load(load));
+        }
+        match load(&self.shared.to_thread_local(), self.shared.objects()) {
+            Ok(data) => BlobData::Ready(data),
+            Err(error) => BlobData::Failed(error),
+        }
     }
 }
 
 struct ReadStream {
-    data: bytes::Bytes,
+    // The part of the file starting at `data_offset`.
+    data: BlobData,
+    data_offset: usize,
     offset: usize,
+    // Loads the next part of the file when we get to the end of `data`, or
+    // `None` if `data` is the whole file.
+    reader: Option<BlobReader>,
+    bytes_read: ByteBudget,
+    // Counts against `FsQuota::max_streams` until the stream is dropped.
+    _slot: StreamSlot,
+}
+
+impl ReadStream {
+    // Take the result of the load if it has finished.
+    fn finish_load(&mut self, result: Result<FsResult<Bytes>, tokio::task::JoinError>) {
+        self.data = match joined(result) {
+            Ok(data) => BlobData::Ready(data),
+            Err(error) => BlobData::Failed(error),
+        };
+    }
+
+    // Start loading the next part of the file if we have read all of `data`.
+    // An empty part means we're at the end.
+    fn load_next(&mut self) {
+        if let (BlobData::Ready(data), Some(reader)) = (&self.data, &self.reader)
+            && !data.is_empty()
+            && self.offset >= self.data_offset + data.len()
+        {
+            self.data = reader.start(self.offset as u64);
+            self.data_offset = self.offset;
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -726,7 +1414,13 @@ impl wasmtime_wasi::p2::Pollable for ReadStream {
     /// connected to. The call to `wasi:io/poll` itself does not return errors,
     /// only a list of ready objects.
     async fn ready(&mut self) {
-        // It's always ready.
+        // It's always ready once the blob (or the next part of it) has
+        // loaded.
+        self.load_next();
+        if let BlobData::Loading(handle) = &mut self.data {
+            let result = handle.await;
+            self.finish_load(result);
+        }
     }
 }
 
@@ -744,24 +1438,97 @@ impl wasmtime_wasi::p2::InputStream for ReadStream {
     /// The [`StreamError`] return value communicates when this stream is
     /// closed, when a read fails, or when a trap should be generated.
     fn read(&mut self, size: usize) -> StreamResult<bytes::Bytes> {
-        if self.offset >= self.data.len() {
+        self.load_next();
+        if let BlobData::Loading(handle) = &mut self.data {
+            match handle.now_or_never() {
+                Some(result) => self.finish_load(result),
+                // Nothing to read yet. The guest should wait on `ready()`.
+                None => return Ok(Bytes::new()),
+            }
+        }
+        let data = match &self.data {
+            BlobData::Ready(data) => data,
+            BlobData::Failed(_) => {
+                // The stream is closed after this, which an empty `data`
+                // says from now on.
+                let BlobData::Failed(error) =
+                    std::mem::replace(&mut self.data, BlobData::Ready(Bytes::new()))
+                else {
+                    unreachable!()
+                };
+                return Err(match error.downcast() {
+                    Ok(code) => StreamError::LastOperationFailed(code.into()),
+                    Err(trap) => StreamError::Trap(trap),
+                });
+            }
+            BlobData::Loading(_) => unreachable!(),
+        };
+        let start = self.offset - self.data_offset;
+        if start >= data.len() {
             Err(StreamError::Closed)
         } else {
-            let size = size.min(self.data.len() - self.offset);
-            let offset = self.offset;
+            let size = size.min(data.len() - start);
+            self.bytes_read
+                .charge(size as u64)
+                .map_err(|code| StreamError::LastOperationFailed(code.into()))?;
             self.offset += size;
-            Ok(self.data.slice(offset..offset + size))
+            Ok(data.slice(start..start + size))
         }
     }
 }
 
-struct HasWasiFs;
+// Writes go straight into the file, so they are visible to everything else
+// that has it open.
+struct WriteStream {
+    file: WrittenFile,
+    // Where the next write goes, or `None` to append.
+    offset: Option<usize>,
+    bytes_written: ByteBudget,
+    // Counts against `FsQuota::max_streams` until the stream is dropped.
+    _slot: StreamSlot,
+}
+
+// How much the guest may write at once. Writes never block, so this is only
+// to keep each write reasonably small.
+const WRITE_BUDGET: usize = 1024 * 1024;
+
+#[async_trait::async_trait]
+impl wasmtime_wasi::p2::Pollable for WriteStream {
+    async fn ready(&mut self) {
+        // Always ready.
+    }
+}
+
+impl wasmtime_wasi::p2::OutputStream for WriteStream {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        let mut contents = self.file.lock();
+        let offset = self.offset.unwrap_or(contents.data.len());
+        self.bytes_written
+            .charge(contents.write_cost(offset, bytes.len()))
+            .map_err(|code| StreamError::LastOperationFailed(code.into()))?;
+        contents.write_at(offset, &bytes);
+        if let Some(next) = &mut self.offset {
+            *next = offset + bytes.len();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(WRITE_BUDGET)
+    }
+}
+
+pub(crate) struct HasWasiFs;
 
 impl HasData for HasWasiFs {
     type Data<'a> = &'a mut WasiState;
 }
 
-pub fn add_to_linker_async(linker: &mut Linker<WasiState>) -> anyhow::Result<()> {
+pub(crate) fn add_to_linker_async(linker: &mut Linker<WasiState>) -> anyhow::Result<()> {
     filesystem::types::add_to_linker::<WasiState, HasWasiFs>(linker, |t| t)?;
     filesystem::preopens::add_to_linker::<WasiState, HasWasiFs>(linker, |t| t)?;
     Ok(())
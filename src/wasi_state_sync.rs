@@ -0,0 +1,340 @@
+//! Synchronous versions of the filesystem host traits, for use with an
+//! `Engine` that doesn't have `async_support` enabled.
+//!
+//! This is modelled on wasmtime-wasi's own `host/filesystem/sync.rs`, except
+//! that it drives the async implementations in `wasi_state` with a trivial
+//! executor instead of `in_tokio`. They never wait on anything that needs a
+//! runtime, so this works without tokio and the two versions can't drift
+//! apart.
+
+use futures::executor::block_on;
+use wasmtime::component::{Linker, Resource};
+use wasmtime_wasi::p2::{
+    FsError, FsResult,
+    bindings::{
+        filesystem::{preopens, types as async_filesystem},
+        sync::{filesystem::types as sync_filesystem, io::streams},
+    },
+};
+
+use crate::wasi_state::{HasWasiFs, WasiState};
+
+impl sync_filesystem::Host for WasiState {
+    fn convert_error_code(&mut self, err: FsError) -> anyhow::Result<sync_filesystem::ErrorCode> {
+        Ok(async_filesystem::Host::convert_error_code(self, err)?.into())
+    }
+
+    fn filesystem_error_code(
+        &mut self,
+        err: Resource<anyhow::Error>,
+    ) -> anyhow::Result<Option<sync_filesystem::ErrorCode>> {
+        Ok(async_filesystem::Host::filesystem_error_code(self, err)?.map(|e| e.into()))
+    }
+}
+
+impl sync_filesystem::HostDescriptor for WasiState {
+    fn advise(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        offset: sync_filesystem::Filesize,
+        len: sync_filesystem::Filesize,
+        advice: sync_filesystem::Advice,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::advise(
+            self,
+            fd,
+            offset,
+            len,
+            advice.into(),
+        ))
+    }
+
+    fn sync_data(&mut self, fd: Resource<sync_filesystem::Descriptor>) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::sync_data(self, fd))
+    }
+
+    fn get_flags(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+    ) -> FsResult<sync_filesystem::DescriptorFlags> {
+        Ok(block_on(async_filesystem::HostDescriptor::get_flags(self, fd))?.into())
+    }
+
+    fn get_type(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+    ) -> FsResult<sync_filesystem::DescriptorType> {
+        Ok(block_on(async_filesystem::HostDescriptor::get_type(self, fd))?.into())
+    }
+
+    fn set_size(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        size: sync_filesystem::Filesize,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::set_size(self, fd, size))
+    }
+
+    fn set_times(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        atim: sync_filesystem::NewTimestamp,
+        mtim: sync_filesystem::NewTimestamp,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::set_times(
+            self,
+            fd,
+            atim.into(),
+            mtim.into(),
+        ))
+    }
+
+    fn read(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        len: sync_filesystem::Filesize,
+        offset: sync_filesystem::Filesize,
+    ) -> FsResult<(Vec<u8>, bool)> {
+        block_on(async_filesystem::HostDescriptor::read(self, fd, len, offset))
+    }
+
+    fn write(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        buf: Vec<u8>,
+        offset: sync_filesystem::Filesize,
+    ) -> FsResult<sync_filesystem::Filesize> {
+        block_on(async_filesystem::HostDescriptor::write(self, fd, buf, offset))
+    }
+
+    fn read_directory(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+    ) -> FsResult<Resource<sync_filesystem::DirectoryEntryStream>> {
+        block_on(async_filesystem::HostDescriptor::read_directory(self, fd))
+    }
+
+    fn sync(&mut self, fd: Resource<sync_filesystem::Descriptor>) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::sync(self, fd))
+    }
+
+    fn create_directory_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        path: String,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::create_directory_at(
+            self, fd, path,
+        ))
+    }
+
+    fn stat(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+    ) -> FsResult<sync_filesystem::DescriptorStat> {
+        Ok(block_on(async_filesystem::HostDescriptor::stat(self, fd))?.into())
+    }
+
+    fn stat_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        path_flags: sync_filesystem::PathFlags,
+        path: String,
+    ) -> FsResult<sync_filesystem::DescriptorStat> {
+        Ok(block_on(async_filesystem::HostDescriptor::stat_at(
+            self,
+            fd,
+            path_flags.into(),
+            path,
+        ))?
+        .into())
+    }
+
+    fn set_times_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        path_flags: sync_filesystem::PathFlags,
+        path: String,
+        atim: sync_filesystem::NewTimestamp,
+        mtim: sync_filesystem::NewTimestamp,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::set_times_at(
+            self,
+            fd,
+            path_flags.into(),
+            path,
+            atim.into(),
+            mtim.into(),
+        ))
+    }
+
+    fn link_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        old_path_flags: sync_filesystem::PathFlags,
+        old_path: String,
+        new_descriptor: Resource<sync_filesystem::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::link_at(
+            self,
+            fd,
+            old_path_flags.into(),
+            old_path,
+            new_descriptor,
+            new_path,
+        ))
+    }
+
+    fn open_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        path_flags: sync_filesystem::PathFlags,
+        path: String,
+        oflags: sync_filesystem::OpenFlags,
+        flags: sync_filesystem::DescriptorFlags,
+    ) -> FsResult<Resource<sync_filesystem::Descriptor>> {
+        block_on(async_filesystem::HostDescriptor::open_at(
+            self,
+            fd,
+            path_flags.into(),
+            path,
+            oflags.into(),
+            flags.into(),
+        ))
+    }
+
+    fn drop(&mut self, fd: Resource<sync_filesystem::Descriptor>) -> anyhow::Result<()> {
+        async_filesystem::HostDescriptor::drop(self, fd)
+    }
+
+    fn readlink_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        path: String,
+    ) -> FsResult<String> {
+        block_on(async_filesystem::HostDescriptor::readlink_at(self, fd, path))
+    }
+
+    fn remove_directory_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        path: String,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::remove_directory_at(
+            self, fd, path,
+        ))
+    }
+
+    fn rename_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        old_path: String,
+        new_fd: Resource<sync_filesystem::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::rename_at(
+            self, fd, old_path, new_fd, new_path,
+        ))
+    }
+
+    fn symlink_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        src_path: String,
+        dest_path: String,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::symlink_at(
+            self, fd, src_path, dest_path,
+        ))
+    }
+
+    fn unlink_file_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        path: String,
+    ) -> FsResult<()> {
+        block_on(async_filesystem::HostDescriptor::unlink_file_at(
+            self, fd, path,
+        ))
+    }
+
+    fn read_via_stream(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        offset: sync_filesystem::Filesize,
+    ) -> FsResult<Resource<streams::InputStream>> {
+        async_filesystem::HostDescriptor::read_via_stream(self, fd, offset)
+    }
+
+    fn write_via_stream(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        offset: sync_filesystem::Filesize,
+    ) -> FsResult<Resource<streams::OutputStream>> {
+        async_filesystem::HostDescriptor::write_via_stream(self, fd, offset)
+    }
+
+    fn append_via_stream(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+    ) -> FsResult<Resource<streams::OutputStream>> {
+        async_filesystem::HostDescriptor::append_via_stream(self, fd)
+    }
+
+    fn is_same_object(
+        &mut self,
+        a: Resource<sync_filesystem::Descriptor>,
+        b: Resource<sync_filesystem::Descriptor>,
+    ) -> anyhow::Result<bool> {
+        block_on(async_filesystem::HostDescriptor::is_same_object(self, a, b))
+    }
+
+    fn metadata_hash(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+    ) -> FsResult<sync_filesystem::MetadataHashValue> {
+        Ok(block_on(async_filesystem::HostDescriptor::metadata_hash(self, fd))?.into())
+    }
+
+    fn metadata_hash_at(
+        &mut self,
+        fd: Resource<sync_filesystem::Descriptor>,
+        path_flags: sync_filesystem::PathFlags,
+        path: String,
+    ) -> FsResult<sync_filesystem::MetadataHashValue> {
+        Ok(block_on(async_filesystem::HostDescriptor::metadata_hash_at(
+            self,
+            fd,
+            path_flags.into(),
+            path,
+        ))?
+        .into())
+    }
+}
+
+impl sync_filesystem::HostDirectoryEntryStream for WasiState {
+    fn read_directory_entry(
+        &mut self,
+        stream: Resource<sync_filesystem::DirectoryEntryStream>,
+    ) -> FsResult<Option<sync_filesystem::DirectoryEntry>> {
+        Ok(block_on(
+            async_filesystem::HostDirectoryEntryStream::read_directory_entry(self, stream),
+        )?
+        .map(|e| e.into()))
+    }
+
+    fn drop(
+        &mut self,
+        stream: Resource<sync_filesystem::DirectoryEntryStream>,
+    ) -> anyhow::Result<()> {
+        async_filesystem::HostDirectoryEntryStream::drop(self, stream)
+    }
+}
+
+// `preopens` has no async functions so the same bindings are used for both.
+pub(crate) fn add_to_linker_sync(linker: &mut Linker<WasiState>) -> anyhow::Result<()> {
+    sync_filesystem::add_to_linker::<WasiState, HasWasiFs>(linker, |t| t)?;
+    preopens::add_to_linker::<WasiState, HasWasiFs>(linker, |t| t)?;
+    Ok(())
+}
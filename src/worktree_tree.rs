@@ -0,0 +1,275 @@
+//! Build a root tree from the files in the working tree, so guests can see
+//! changes that haven't been staged yet.
+
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use gix::{
+    AttributeStack, ObjectId, Repository,
+    attrs::{StateRef, search::Outcome},
+    bstr::{BStr, BString, ByteSlice, ByteVec},
+    filter::plumbing::eol::AutoCrlf,
+    index::entry::{Mode, Stage, Stat, stat::Options as StatOptions},
+    objs::{self, tree::EntryKind},
+    worktree::stack::state::{attributes, ignore},
+};
+
+use crate::{index_tree, shared_repo::ObjectCache};
+
+// Read every tracked file from the working tree (and optionally every
+// untracked file that isn't ignored), add their contents to the object cache
+// and build a tree of them. Returns the root tree's ID.
+//
+// This is a snapshot taken when it's called; later changes to the working
+// tree aren't seen. Tracked files that have been deleted from the working
+// tree are left out, and submodules are taken from the index. Tracked files
+// whose stat info matches the index aren't read at all, like `git status`;
+// their blobs are loaded from the object database when the guest reads them.
+// That's only done for files that Git wouldn't convert on checkout though,
+// since otherwise the blob isn't what's in the working tree.
+pub(crate) fn tree_from_worktree(
+    repo: &Repository,
+    objects: &ObjectCache,
+    include_untracked: bool,
+) -> Result<ObjectId> {
+    let workdir = repo.workdir().context("repository has no working tree")?;
+    let index = repo.index_or_empty().context("reading index")?;
+    let hash_kind = repo.object_hash();
+
+    let stat_options = StatOptions::default();
+    let mut conversions = Conversions::new(repo, &index)?;
+    let mut entries = Vec::new();
+    let mut tracked = HashSet::new();
+    for entry in index.entries() {
+        let path = entry.path(&index);
+        // Conflicted files appear once per stage but there is only one copy
+        // in the working tree.
+        if !tracked.insert(path.to_owned()) {
+            continue;
+        }
+        // Submodules, and whole directories in a sparse index, aren't
+        // files in the working tree.
+        if entry.mode == Mode::COMMIT || entry.mode == Mode::DIR {
+            let kind = if entry.mode == Mode::COMMIT {
+                EntryKind::Commit
+            } else {
+                EntryKind::Tree
+            };
+            entries.push((path.to_owned(), kind, entry.id));
+            continue;
+        }
+        // Keep the index's idea of whether the file is executable, like Git
+        // does when `core.fileMode` is false.
+        let kind = match entry.stage() {
+            Stage::Unconflicted => entry
+                .mode
+                .to_tree_entry_mode()
+                .map_or(EntryKind::Blob, |mode| mode.kind()),
+            _ => EntryKind::Blob,
+        };
+        // Files changed in the same second the index was written are racy:
+        // their stat info can match even though they changed.
+        if entry.stage() == Stage::Unconflicted
+            && !entry.stat.is_racy(index.timestamp(), stat_options)
+            && stat_matches(workdir, path, entry, stat_options)
+            && !conversions.may_convert(path)?
+        {
+            entries.push((path.to_owned(), kind, entry.id));
+            continue;
+        }
+        if let Some(entry) = read_file(workdir, path, kind, hash_kind, objects)? {
+            entries.push(entry);
+        }
+    }
+
+    if include_untracked {
+        let mut excludes = repo
+            .excludes(
+                &index,
+                None,
+                ignore::Source::WorktreeThenIdMappingIfNotSkipped,
+            )
+            .context("loading ignore files")?;
+        let mut untracked = Vec::new();
+        let mut dirs = vec![BString::default()];
+        while let Some(dir) = dirs.pop() {
+            let host_dir = workdir.join(gix::path::from_bstr(dir.as_bstr()));
+            for dir_entry in std::fs::read_dir(&host_dir)
+                .with_context(|| format!("reading {}", host_dir.display()))?
+            {
+                let dir_entry = dir_entry?;
+                let name = gix::path::os_str_into_bstr(&dir_entry.file_name())?.to_owned();
+                if name == ".git" {
+                    continue;
+                }
+                let mut path = dir.clone();
+                if !path.is_empty() {
+                    path.push_byte(b'/');
+                }
+                path.push_str(&name);
+                if tracked.contains(&path) {
+                    continue;
+                }
+                let file_type = dir_entry.file_type()?;
+                let mode = if file_type.is_dir() {
+                    Mode::DIR
+                } else if file_type.is_symlink() {
+                    Mode::SYMLINK
+                } else {
+                    Mode::FILE
+                };
+                if excludes.at_entry(path.as_bstr(), Some(mode))?.is_excluded() {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    // Nested repositories aren't part of this one.
+                    if !dir_entry.path().join(".git").exists() {
+                        dirs.push(path);
+                    }
+                } else {
+                    untracked.push((path, mode));
+                }
+            }
+        }
+        for (path, mode) in untracked {
+            let kind = if mode == Mode::SYMLINK {
+                EntryKind::Link
+            } else if is_executable(&workdir.join(gix::path::from_bstr(path.as_bstr())))? {
+                EntryKind::BlobExecutable
+            } else {
+                EntryKind::Blob
+            };
+            entries.extend(read_file(
+                workdir,
+                path.as_bstr(),
+                kind,
+                hash_kind,
+                objects,
+            )?);
+        }
+    }
+
+    index_tree::tree_from_entries(entries, hash_kind, objects)
+}
+
+// Read a file (or symlink) from the working tree and put its contents in the
+// object cache. Returns `None` if it doesn't exist.
+fn read_file(
+    workdir: &Path,
+    path: &BStr,
+    kind: EntryKind,
+    hash_kind: gix::hash::Kind,
+    objects: &ObjectCache,
+) -> Result<Option<(BString, EntryKind, ObjectId)>> {
+    let host_path = workdir.join(gix::path::from_bstr(path));
+    let metadata = match std::fs::symlink_metadata(&host_path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => {
+            return Err(error).with_context(|| format!("reading {}", host_path.display()));
+        }
+    };
+
+    // Git stores a symlink's target as the blob contents.
+    let (kind, data) = if metadata.is_symlink() {
+        let target = std::fs::read_link(&host_path)?;
+        let target = gix::path::into_bstr(target);
+        let target = gix::path::to_unix_separators_on_windows(target).into_owned();
+        (EntryKind::Link, Vec::from(target))
+    } else if metadata.is_file() {
+        let kind = if kind == EntryKind::Link {
+            EntryKind::Blob
+        } else {
+            kind
+        };
+        let data = std::fs::read(&host_path)
+            .with_context(|| format!("reading {}", host_path.display()))?;
+        (kind, data)
+    } else {
+        // E.g. a tracked file that has been replaced by a directory.
+        return Ok(None);
+    };
+
+    let id = objs::compute_hash(hash_kind, objs::Kind::Blob, &data)?;
+    objects.insert_blob(id, Bytes::from(data));
+    Ok(Some((path.to_owned(), kind, id)))
+}
+
+// Works out which files Git might convert between their blobs and the working
+// tree (line endings, `ident`, filter drivers or `working-tree-encoding`).
+struct Conversions<'repo> {
+    attributes: AttributeStack<'repo>,
+    matches: Outcome,
+    // `core.autocrlf=true` converts every text file, which could be any file.
+    autocrlf: bool,
+}
+
+impl<'repo> Conversions<'repo> {
+    fn new(repo: &'repo Repository, index: &gix::index::State) -> Result<Self> {
+        let attributes = repo
+            .attributes_only(index, attributes::Source::WorktreeThenIdMapping)
+            .context("loading .gitattributes")?;
+        let matches = attributes.selected_attribute_matches([
+            "text",
+            "eol",
+            "crlf",
+            "ident",
+            "filter",
+            "working-tree-encoding",
+        ]);
+        let options = gix::filter::Pipeline::options(repo).context("reading filter config")?;
+        Ok(Self {
+            attributes,
+            matches,
+            autocrlf: options.eol_config.auto_crlf == AutoCrlf::Enabled,
+        })
+    }
+
+    // Whether the file at `path` might be converted. Errs on the side of yes.
+    fn may_convert(&mut self, path: &BStr) -> Result<bool> {
+        if self.autocrlf {
+            return Ok(true);
+        }
+        let platform = self.attributes.at_entry(path, Some(Mode::FILE))?;
+        self.matches.reset();
+        platform.matching_attributes(&mut self.matches);
+        Ok(self.matches.iter_selected().any(|attribute| {
+            matches!(
+                attribute.assignment.state,
+                StateRef::Set | StateRef::Value(_)
+            )
+        }))
+    }
+}
+
+// Whether a tracked file's stat info (and type) in the working tree matches
+// its index entry.
+fn stat_matches(
+    workdir: &Path,
+    path: &BStr,
+    entry: &gix::index::Entry,
+    options: StatOptions,
+) -> bool {
+    let host_path = workdir.join(gix::path::from_bstr(path));
+    let Ok(metadata) = gix::index::fs::Metadata::from_path_no_follow(&host_path) else {
+        return false;
+    };
+    let same_type = if entry.mode == Mode::SYMLINK {
+        metadata.is_symlink()
+    } else {
+        metadata.is_file()
+    };
+    same_type && Stat::from_fs(&metadata).is_ok_and(|stat| stat.matches(&entry.stat, options))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> Result<bool> {
+    use std::os::unix::fs::PermissionsExt as _;
+    Ok(std::fs::metadata(path)?.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> Result<bool> {
+    Ok(false)
+}
@@ -0,0 +1,122 @@
+//! Hiding or forbidding paths with an `AccessPolicy`.
+
+mod common;
+
+use common::{Fixture, run_ls};
+use gix::bstr::ByteSlice as _;
+use wasmtime_fs_demo::{AccessPolicy, DeniedBehaviour, Runner, RunnerBuilder};
+
+fn is_denied(policy: &AccessPolicy, path: &str, is_dir: bool) -> bool {
+    policy.is_denied(path.as_bytes().as_bstr(), is_dir)
+}
+
+#[test]
+fn last_matching_rule_wins() {
+    let mut policy = AccessPolicy::default();
+    policy.deny("*.txt").unwrap();
+    policy.allow("notes.txt").unwrap();
+    assert!(is_denied(&policy, "other.txt", false));
+    assert!(!is_denied(&policy, "notes.txt", false));
+    assert!(!is_denied(&policy, "notes.md", false));
+
+    policy.deny("notes.*").unwrap();
+    assert!(is_denied(&policy, "notes.txt", false));
+}
+
+#[test]
+fn negated_patterns_allow_again() {
+    let policy = AccessPolicy::from_gitignore_format(
+        "# Keys\nsecrets/*\n!secrets/public.pem\n",
+        DeniedBehaviour::Hide,
+    )
+    .unwrap();
+    assert!(is_denied(&policy, "secrets/private.pem", false));
+    assert!(!is_denied(&policy, "secrets/public.pem", false));
+    assert!(!is_denied(&policy, "secrets", true));
+}
+
+#[test]
+fn directory_only_patterns() {
+    let mut policy = AccessPolicy::default();
+    policy.deny("secrets/").unwrap();
+    assert!(is_denied(&policy, "secrets", true));
+    assert!(is_denied(&policy, "nested/secrets", true));
+    // A file with the same name isn't a directory.
+    assert!(!is_denied(&policy, "secrets", false));
+}
+
+fn fixture(name: &str) -> Fixture {
+    Fixture::with_files(
+        name,
+        &[
+            ("notes.txt", "notes\n"),
+            ("allowed/readme.txt", "readme\n"),
+            ("secrets/key.txt", "hunter2\n"),
+        ],
+    )
+}
+
+fn builder(fixture: &Fixture, denied: DeniedBehaviour) -> RunnerBuilder {
+    let mut policy = AccessPolicy::new(denied);
+    policy.deny("secrets/").unwrap();
+    Runner::builder()
+        .repo(fixture.path("src"))
+        .access_policy(policy)
+}
+
+#[test]
+fn hidden_paths_are_not_listed() {
+    let fixture = fixture("policy_hide_list");
+    let listing = run_ls(builder(&fixture, DeniedBehaviour::Hide), &[]);
+    assert!(listing.contains("readme.txt"), "{listing}");
+    assert!(!listing.contains("secrets"), "{listing}");
+    assert!(!listing.contains("key.txt"), "{listing}");
+}
+
+#[test]
+fn forbidden_paths_are_listed_but_not_opened() {
+    let fixture = fixture("policy_forbid_list");
+    let listing = run_ls(builder(&fixture, DeniedBehaviour::Forbid), &[]);
+    assert!(listing.contains("secrets"), "{listing}");
+    assert!(!listing.contains("key.txt"), "{listing}");
+}
+
+#[test]
+fn hidden_paths_do_not_exist() {
+    let fixture = fixture("policy_hide_open");
+    let output = run_ls(
+        builder(&fixture, DeniedBehaviour::Hide),
+        &["--cat", "notes.txt", "secrets/key.txt"],
+    );
+    assert_eq!(output, "notes\nsecrets/key.txt: NotFound\n");
+}
+
+#[test]
+fn forbidden_paths_give_access_errors() {
+    let fixture = fixture("policy_forbid_open");
+    let output = run_ls(
+        builder(&fixture, DeniedBehaviour::Forbid),
+        &["--cat", "notes.txt", "secrets/key.txt"],
+    );
+    assert_eq!(output, "notes\nsecrets/key.txt: PermissionDenied\n");
+}
+
+#[test]
+fn parent_directories_do_not_bypass_the_policy() {
+    let fixture = fixture("policy_traversal");
+    let output = run_ls(
+        builder(&fixture, DeniedBehaviour::Hide),
+        &[
+            "--cat",
+            "allowed/../notes.txt",
+            "allowed/../secrets/key.txt",
+            "allowed/../secrets/../secrets/key.txt",
+        ],
+    );
+    assert_eq!(
+        output,
+        "notes\n\
+         allowed/../secrets/key.txt: NotFound\n\
+         allowed/../secrets/../secrets/key.txt: NotFound\n"
+    );
+}
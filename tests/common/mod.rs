@@ -0,0 +1,128 @@
+//! Helpers shared by the integration tests. Fixtures are built with the `git`
+//! command line tool.
+
+// Each test binary only uses some of these.
+#![allow(dead_code)]
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use wasmtime_fs_demo::{RunOutcome, RunnerBuilder};
+
+// A scratch directory that is deleted when dropped.
+pub struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    // An empty scratch directory.
+    pub fn empty(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("wasmtime_fs_demo_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self { dir }
+    }
+
+    // Create a repository at `<dir>/src` with one commit.
+    pub fn new(name: &str) -> Self {
+        Self::with_files(
+            name,
+            &[("hello.txt", "Hello\n"), ("docs/readme.md", "# Readme\n")],
+        )
+    }
+
+    // Create a repository at `<dir>/src` with one commit containing `files`.
+    pub fn with_files(name: &str, files: &[(&str, &str)]) -> Self {
+        let fixture = Self::empty(name);
+        fixture.git(&["init", "--quiet", "--initial-branch=main", "src"]);
+        fixture.write_files("src", files);
+        fixture.commit("src", "Initial commit");
+        fixture
+    }
+
+    // Write files relative to the scratch directory, creating parent
+    // directories as needed.
+    pub fn write_files(&self, dir: &str, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = self.dir.join(dir).join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+    }
+
+    // Commit everything in the repository at `dir`.
+    pub fn commit(&self, dir: &str, message: &str) {
+        self.git(&["-C", dir, "add", "--all"]);
+        self.git(&[
+            "-C",
+            dir,
+            "-c",
+            "user.name=Test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "--allow-empty",
+            "--message",
+            message,
+        ]);
+    }
+
+    // Make a bare partial clone of the repository at `src` in `dest`, without
+    // any blobs.
+    pub fn partial_clone(&self, src: &str, dest: &str) {
+        self.git(&["-C", src, "config", "uploadpack.allowFilter", "true"]);
+        let url = format!("file://{}", self.path(src).display());
+        self.git(&[
+            "clone",
+            "--quiet",
+            "--bare",
+            "--filter=blob:none",
+            &url,
+            dest,
+        ]);
+    }
+
+    pub fn git(&self, args: &[&str]) {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(&self.dir)
+            .status()
+            .expect("running git");
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn wasi_ls() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("wasi_ls.wasm")
+}
+
+// Run `wasi_ls` with `args` and return what it printed. With no arguments it
+// lists the whole tree; `--cat PATH...` prints files instead.
+pub fn run_ls(builder: RunnerBuilder, args: &[&str]) -> String {
+    let output = builder
+        .component(wasi_ls())
+        .args(args)
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap();
+    assert!(
+        matches!(output.outcome, RunOutcome::Exited(0)),
+        "{:?}",
+        output.outcome
+    );
+    String::from_utf8(output.stdout.unwrap().to_vec()).unwrap()
+}
@@ -0,0 +1,96 @@
+//! Caching compiled components on disk.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::{Fixture, run_ls};
+use wasmtime_fs_demo::{Runner, RunnerBuilder};
+
+fn builder(fixture: &Fixture) -> RunnerBuilder {
+    Runner::builder()
+        .repo(fixture.path("src"))
+        .component_cache(fixture.path("cache"))
+}
+
+// The cache entries, sorted.
+fn entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    entries
+}
+
+#[test]
+fn second_build_uses_the_cache() {
+    let fixture = Fixture::new("cache_hit");
+    run_ls(builder(&fixture), &[]);
+    let [entry] = &entries(&fixture.path("cache"))[..] else {
+        panic!("expected one cache entry");
+    };
+    let before = std::fs::metadata(entry).unwrap();
+
+    assert_eq!(
+        run_ls(builder(&fixture), &["--cat", "hello.txt"]),
+        "Hello\n"
+    );
+    // Entries are written by renaming a new file into place, so one that
+    // wasn't rewritten is still the same file.
+    assert_eq!(entries(&fixture.path("cache")), std::slice::from_ref(entry));
+    let after = std::fs::metadata(entry).unwrap();
+    assert_eq!(after.modified().unwrap(), before.modified().unwrap());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt as _;
+        assert_eq!(after.ino(), before.ino());
+    }
+}
+
+#[test]
+fn corrupt_entries_are_recompiled() {
+    let fixture = Fixture::new("cache_corrupt");
+    run_ls(builder(&fixture), &[]);
+    let [entry] = &entries(&fixture.path("cache"))[..] else {
+        panic!("expected one cache entry");
+    };
+    let good = std::fs::read(entry).unwrap();
+    // E.g. truncated by a full disk.
+    std::fs::write(entry, &good[..good.len() / 2]).unwrap();
+
+    assert_eq!(
+        run_ls(builder(&fixture), &["--cat", "hello.txt"]),
+        "Hello\n"
+    );
+    assert_eq!(std::fs::read(entry).unwrap(), good);
+}
+
+// An entry compiled with different settings (here without fuel metering)
+// can't be loaded by an engine that meters fuel.
+#[test]
+fn incompatible_entries_are_recompiled() {
+    let fixture = Fixture::new("cache_incompatible");
+    run_ls(builder(&fixture), &[]);
+    run_ls(builder(&fixture).fuel(u64::MAX), &[]);
+    let [first, second] = &entries(&fixture.path("cache"))[..] else {
+        panic!("expected a cache entry for each engine");
+    };
+    let first_data = std::fs::read(first).unwrap();
+    let second_data = std::fs::read(second).unwrap();
+    assert_ne!(first_data, second_data);
+    // Swap them so each engine finds the other's code under its key.
+    std::fs::write(first, &second_data).unwrap();
+    std::fs::write(second, &first_data).unwrap();
+
+    assert_eq!(
+        run_ls(builder(&fixture).fuel(u64::MAX), &["--cat", "hello.txt"]),
+        "Hello\n"
+    );
+    assert_eq!(
+        run_ls(builder(&fixture), &["--cat", "hello.txt"]),
+        "Hello\n"
+    );
+    assert_eq!(std::fs::read(first).unwrap(), first_data);
+    assert_eq!(std::fs::read(second).unwrap(), second_data);
+}
@@ -0,0 +1,76 @@
+//! The virtual `.gitinfo/` directory.
+
+mod common;
+
+use std::process::Command;
+
+use common::{Fixture, run_ls};
+use wasmtime_fs_demo::Runner;
+
+// What `git` prints for `args`, run in the fixture's repository.
+fn git_output(fixture: &Fixture, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(fixture.path("src"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {args:?} failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn git_info(fixture: &Fixture, rev: &str, file: &str) -> String {
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .rev(rev)
+        .git_info();
+    run_ls(builder, &["--cat", &format!(".gitinfo/{file}")])
+}
+
+// An older commit with a tag and a branch, and `main` checked out.
+fn fixture(name: &str) -> Fixture {
+    let fixture = Fixture::new(name);
+    fixture.git(&["-C", "src", "tag", "v1"]);
+    fixture.git(&["-C", "src", "branch", "release"]);
+    fixture.write_files("src", &[("hello.txt", "Changed\n")]);
+    fixture.commit("src", "Change");
+    fixture
+}
+
+#[test]
+fn describes_the_commit() {
+    let fixture = fixture("gitinfo");
+
+    let commit = git_output(&fixture, &["rev-parse", "v1"]);
+    assert_eq!(git_info(&fixture, "v1", "commit"), commit);
+    assert_eq!(
+        git_info(&fixture, "v1", "tree"),
+        git_output(&fixture, &["rev-parse", "v1^{tree}"])
+    );
+    assert_eq!(git_info(&fixture, "v1", "message"), "Initial commit\n");
+    let author = git_info(&fixture, "v1", "author");
+    assert!(author.starts_with("Test <test@example.com> "), "{author}");
+}
+
+#[test]
+fn refs_point_at_the_commit() {
+    let fixture = fixture("gitinfo_refs");
+    assert_eq!(
+        git_info(&fixture, "v1", "refs"),
+        "refs/heads/release\nrefs/tags/v1\n"
+    );
+    assert_eq!(git_info(&fixture, "main", "refs"), "refs/heads/main\n");
+}
+
+#[test]
+fn head_is_the_checked_out_branch() {
+    let fixture = fixture("gitinfo_head");
+    // Not the revision that is mounted.
+    assert_eq!(git_info(&fixture, "v1", "HEAD"), "ref: refs/heads/main\n");
+
+    fixture.git(&["-C", "src", "checkout", "--quiet", "--detach", "v1"]);
+    assert_eq!(
+        git_info(&fixture, "main", "HEAD"),
+        git_output(&fixture, &["rev-parse", "v1"])
+    );
+}
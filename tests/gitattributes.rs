@@ -0,0 +1,51 @@
+//! Converting files according to `.gitattributes`.
+
+mod common;
+
+use common::{Fixture, run_ls};
+use wasmtime_fs_demo::{RunOutcome, Runner};
+
+fn fixture(name: &str) -> Fixture {
+    Fixture::with_files(
+        name,
+        &[
+            (".gitattributes", "*.crlf eol=crlf\n"),
+            // The same blob at two paths that convert differently.
+            ("same.crlf", "one\ntwo\n"),
+            ("same.txt", "one\ntwo\n"),
+        ],
+    )
+}
+
+#[test]
+fn converts_line_endings_by_path() {
+    let fixture = fixture("gitattributes_eol");
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .apply_gitattributes();
+    let output = run_ls(builder, &["--cat", "same.crlf", "same.txt"]);
+    assert_eq!(output, "one\r\ntwo\r\none\ntwo\n");
+}
+
+#[test]
+fn concurrent_runs_share_converted_files() {
+    let fixture = fixture("gitattributes_concurrent");
+    let runner = Runner::builder()
+        .repo(fixture.path("src"))
+        .apply_gitattributes()
+        .component(common::wasi_ls())
+        .args(["--cat", "same.crlf", "same.txt"])
+        .capture_output(1 << 20)
+        .build()
+        .unwrap();
+    // Each run converts with its own pipeline, and whichever finishes first
+    // leaves the results in the cache for the other.
+    std::thread::scope(|scope| {
+        let runs: Vec<_> = (0..2).map(|_| scope.spawn(|| runner.run_sync())).collect();
+        for run in runs {
+            let output = run.join().unwrap().unwrap();
+            assert!(matches!(output.outcome, RunOutcome::Exited(0)));
+            assert_eq!(output.stdout.unwrap(), "one\r\ntwo\r\none\ntwo\n");
+        }
+    });
+}
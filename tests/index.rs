@@ -0,0 +1,96 @@
+//! Running against the index (staging area) instead of a commit.
+
+mod common;
+
+use std::process::Command;
+
+use common::{Fixture, run_ls, wasi_ls};
+use wasmtime_fs_demo::Runner;
+
+#[test]
+fn sees_staged_changes_only() {
+    let fixture = Fixture::new("index_staged");
+    fixture.write_files("src", &[("hello.txt", "Staged\n"), ("staged.txt", "New\n")]);
+    fixture.git(&["-C", "src", "add", "hello.txt", "staged.txt"]);
+    // Not staged, so not visible.
+    fixture.write_files(
+        "src",
+        &[("hello.txt", "Unstaged\n"), ("unstaged.txt", "New\n")],
+    );
+
+    let output = run_ls(
+        Runner::builder().repo(fixture.path("src")).index(),
+        &["--cat", "hello.txt", "staged.txt", "unstaged.txt"],
+    );
+    assert_eq!(output, "Staged\nNew\nunstaged.txt: NotFound\n");
+}
+
+#[test]
+fn files_removed_from_the_index_are_gone() {
+    let fixture = Fixture::new("index_removed");
+    fixture.git(&["-C", "src", "rm", "--quiet", "--cached", "docs/readme.md"]);
+    assert!(fixture.path("src/docs/readme.md").exists());
+
+    let builder = Runner::builder().repo(fixture.path("src")).index();
+    assert_eq!(
+        run_ls(builder.clone(), &["--cat", "docs/readme.md"]),
+        "docs/readme.md: NotFound\n"
+    );
+    // Its directory is empty, so that's gone too.
+    assert!(!run_ls(builder, &[]).contains("docs"));
+}
+
+#[test]
+fn intent_to_add_files_are_left_out() {
+    let fixture = Fixture::new("index_intent_to_add");
+    fixture.write_files("src", &[("new.txt", "New\n")]);
+    fixture.git(&["-C", "src", "add", "--intent-to-add", "new.txt"]);
+
+    let output = run_ls(
+        Runner::builder().repo(fixture.path("src")).index(),
+        &["--cat", "hello.txt", "new.txt"],
+    );
+    assert_eq!(output, "Hello\nnew.txt: NotFound\n");
+}
+
+#[test]
+fn conflicts_are_an_error() {
+    let fixture = Fixture::new("index_conflict");
+    fixture.git(&["-C", "src", "checkout", "--quiet", "-b", "other"]);
+    fixture.write_files("src", &[("hello.txt", "Other\n")]);
+    fixture.commit("src", "Other");
+    fixture.git(&["-C", "src", "checkout", "--quiet", "main"]);
+    fixture.write_files("src", &[("hello.txt", "Main\n")]);
+    fixture.commit("src", "Main");
+    // This fails, leaving stages 1, 2 and 3 of `hello.txt` in the index.
+    let status = Command::new("git")
+        .args([
+            "-C",
+            "src",
+            "-c",
+            "user.name=Test",
+            "-c",
+            "user.email=test@example.com",
+            "merge",
+            "--quiet",
+            "other",
+        ])
+        .current_dir(fixture.path(""))
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    let result = Runner::builder()
+        .repo(fixture.path("src"))
+        .index()
+        .component(wasi_ls())
+        .build();
+    let Err(error) = result else {
+        panic!("built a runner for a conflicted index");
+    };
+    assert!(
+        error.to_string().contains("conflicts in hello.txt"),
+        "{error:#}"
+    );
+}
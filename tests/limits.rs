@@ -0,0 +1,67 @@
+//! Guest memory limits.
+
+mod common;
+
+use common::{Fixture, wasi_ls};
+use wasmtime_fs_demo::{GuestLimits, LimitExceeded, RunOutcome, RunOutput, Runner};
+
+const PAGE: usize = 64 * 1024;
+const GIB: usize = 1 << 30;
+
+fn grow(fixture: &Fixture, max_memory_bytes: usize, pages: &[&str]) -> RunOutput {
+    let mut args = vec!["--grow"];
+    args.extend(pages);
+    Runner::builder()
+        .repo(fixture.path("src"))
+        .component(wasi_ls())
+        .args(args)
+        .limits(GuestLimits {
+            max_memory_bytes: Some(max_memory_bytes),
+            ..Default::default()
+        })
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap()
+}
+
+#[test]
+fn exceeding_max_memory_traps() {
+    let fixture = Fixture::new("limits_memory");
+    let limit = 64 << 20;
+    let output = grow(&fixture, limit, &["1", "2048"]);
+    let RunOutcome::Trapped(error) = output.outcome else {
+        panic!("{:?}", output.outcome);
+    };
+    let exceeded = error
+        .downcast_ref::<LimitExceeded>()
+        .unwrap_or_else(|| panic!("{error:?}"));
+    assert_eq!(exceeded.resource, "memory");
+    assert_eq!(exceeded.limit, limit);
+    assert!(exceeded.desired > limit);
+    // The first, small growth worked.
+    let stdout = String::from_utf8(output.stdout.unwrap().to_vec()).unwrap();
+    assert_eq!(stdout.lines().count(), 1, "{stdout}");
+}
+
+// Growing past the 4 GiB a 32-bit memory can hold is allowed by the limit,
+// but then fails. It shouldn't count against what's left.
+#[test]
+fn failed_growth_is_not_counted() {
+    let fixture = Fixture::new("limits_memory_failed_growth");
+    let four_gib_pages = (4 * GIB / PAGE).to_string();
+    let one_gib_pages = (GIB / PAGE).to_string();
+    let output = grow(
+        &fixture,
+        4 * GIB + GIB / 2,
+        &[&four_gib_pages, &one_gib_pages],
+    );
+    assert!(
+        matches!(output.outcome, RunOutcome::Exited(0)),
+        "{:?}",
+        output.outcome
+    );
+    let stdout = String::from_utf8(output.stdout.unwrap().to_vec()).unwrap();
+    let results: Vec<isize> = stdout.lines().map(|line| line.parse().unwrap()).collect();
+    assert_eq!(results[0], -1);
+    assert!(results[1] > 0, "{stdout}");
+}
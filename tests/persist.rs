@@ -0,0 +1,283 @@
+//! Persisting a run's changes only when it exits with 0.
+
+mod common;
+
+use std::process::Command;
+
+use common::{Fixture, wasi_ls};
+use wasmtime_fs_demo::{Persist, RunOutcome, RunOutput, Runner, RunnerBuilder};
+
+fn run(builder: RunnerBuilder, args: &[&str]) -> RunOutput {
+    builder
+        .component(wasi_ls())
+        .args(args)
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap()
+}
+
+// What `git` prints for `args`, run in the fixture's repository.
+fn git_output(fixture: &Fixture, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(fixture.path("src"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {args:?} failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn branch_exists(fixture: &Fixture, name: &str) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(fixture.path("src"))
+        .args(["rev-parse", "--verify", "--quiet", name])
+        .output()
+        .unwrap()
+        .status
+        .success()
+}
+
+// A fixture whose repository has an author and committer configured.
+fn fixture_with_identity(name: &str) -> Fixture {
+    let fixture = Fixture::new(name);
+    fixture.git(&["-C", "src", "config", "user.name", "Test"]);
+    fixture.git(&["-C", "src", "config", "user.email", "test@example.com"]);
+    fixture
+}
+
+fn branch(name: &str) -> Persist {
+    Persist::Branch {
+        name: name.to_string(),
+        message: "Changes from the run\n".to_string(),
+    }
+}
+
+#[test]
+fn directory_is_updated_on_success() {
+    let fixture = Fixture::new("persist_directory");
+    fixture.git(&["clone", "--quiet", "src", "dest"]);
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .persist(Persist::Directory(fixture.path("dest")));
+    let output = run(
+        builder,
+        &[
+            "--write",
+            "hello.txt",
+            "Changed\n",
+            "--rm",
+            "docs/readme.md",
+        ],
+    );
+    assert!(matches!(output.outcome, RunOutcome::Exited(0)));
+    assert!(output.persisted);
+    assert_eq!(
+        std::fs::read_to_string(fixture.path("dest/hello.txt")).unwrap(),
+        "Changed\n"
+    );
+    assert!(!fixture.path("dest/docs").exists());
+}
+
+#[test]
+fn directory_is_untouched_on_failure() {
+    let fixture = Fixture::new("persist_directory_failure");
+    fixture.git(&["clone", "--quiet", "src", "dest"]);
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .persist(Persist::Directory(fixture.path("dest")));
+
+    let output = run(
+        builder.clone(),
+        &["--write", "hello.txt", "Changed\n", "--exit", "1"],
+    );
+    assert!(matches!(output.outcome, RunOutcome::Exited(1)));
+    assert!(!output.persisted);
+    // The changes are still reported.
+    assert_eq!(output.changes.unwrap().files().len(), 1);
+
+    // An unknown command makes the guest panic halfway through.
+    let output = run(builder, &["--write", "hello.txt", "Changed\n", "--bogus"]);
+    assert!(matches!(output.outcome, RunOutcome::Trapped(_)));
+    assert!(!output.persisted);
+
+    assert_eq!(
+        std::fs::read_to_string(fixture.path("dest/hello.txt")).unwrap(),
+        "Hello\n"
+    );
+}
+
+#[test]
+fn failed_directory_writes_are_rolled_back() {
+    let fixture = Fixture::new("persist_directory_rollback");
+    fixture.git(&["clone", "--quiet", "src", "dest"]);
+    // A file where the run creates a directory.
+    fixture.write_files("dest", &[("new", "In the way\n")]);
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .persist(Persist::Directory(fixture.path("dest")));
+    let error = builder
+        .component(wasi_ls())
+        .args([
+            "--write",
+            "hello.txt",
+            "Changed\n",
+            "--rm",
+            "docs/readme.md",
+            "--mkdir",
+            "new",
+            "--write",
+            "new/file.txt",
+            "New\n",
+        ])
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap_err();
+    assert!(format!("{error:#}").contains("new/file.txt"), "{error:#}");
+
+    let dest = fixture.path("dest");
+    assert_eq!(
+        std::fs::read_to_string(dest.join("hello.txt")).unwrap(),
+        "Hello\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("docs/readme.md")).unwrap(),
+        "# Readme\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("new")).unwrap(),
+        "In the way\n"
+    );
+    let mut names: Vec<_> = std::fs::read_dir(&dest)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, [".git", "docs", "hello.txt", "new"]);
+}
+
+#[test]
+fn branch_is_committed_on_success() {
+    let fixture = fixture_with_identity("persist_branch");
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .rev("main")
+        .persist(branch("output"));
+    let output = run(
+        builder,
+        &[
+            "--write",
+            "hello.txt",
+            "Changed\n",
+            "--rm",
+            "docs/readme.md",
+            "--mkdir",
+            "new",
+            "--write",
+            "new/file.txt",
+            "New\n",
+        ],
+    );
+    assert!(matches!(output.outcome, RunOutcome::Exited(0)));
+    assert!(output.persisted);
+
+    assert_eq!(
+        git_output(&fixture, &["ls-tree", "-r", "--name-only", "output"]),
+        "hello.txt\nnew/file.txt\n"
+    );
+    assert_eq!(
+        git_output(&fixture, &["show", "output:hello.txt"]),
+        "Changed\n"
+    );
+    assert_eq!(
+        git_output(&fixture, &["rev-parse", "output~1"]),
+        git_output(&fixture, &["rev-parse", "main"])
+    );
+    assert_eq!(
+        git_output(&fixture, &["log", "-1", "--format=%an %s", "output"]),
+        "Test Changes from the run\n"
+    );
+}
+
+#[test]
+fn branch_is_not_created_on_failure() {
+    let fixture = fixture_with_identity("persist_branch_failure");
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .rev("main")
+        .persist(branch("output"));
+    let output = run(
+        builder,
+        &["--write", "hello.txt", "Changed\n", "--exit", "1"],
+    );
+    assert!(matches!(output.outcome, RunOutcome::Exited(1)));
+    assert!(!output.persisted);
+    assert!(!branch_exists(&fixture, "output"));
+}
+
+#[test]
+fn nothing_is_committed_without_changes() {
+    let fixture = fixture_with_identity("persist_branch_unchanged");
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .rev("main")
+        .persist(branch("output"));
+    let output = run(builder, &["--print", "hello.txt"]);
+    assert!(output.persisted);
+    assert!(!branch_exists(&fixture, "output"));
+}
+
+#[test]
+fn branch_that_has_moved_is_not_overwritten() {
+    let fixture = fixture_with_identity("persist_branch_moved");
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .rev("main")
+        .persist(branch("main"))
+        .component(wasi_ls())
+        .args(["--write", "hello.txt", "Changed\n"]);
+    let runner = builder.build().unwrap();
+    fixture.write_files("src", &[("hello.txt", "Meanwhile\n")]);
+    fixture.commit("src", "Meanwhile");
+    let before = git_output(&fixture, &["rev-parse", "main"]);
+
+    let error = runner.run_sync().unwrap_err();
+    assert!(
+        format!("{error:#}").contains("refs/heads/main"),
+        "{error:#}"
+    );
+    assert_eq!(git_output(&fixture, &["rev-parse", "main"]), before);
+}
+
+#[test]
+fn subdir_changes_are_committed_in_place() {
+    let fixture = fixture_with_identity("persist_branch_subdir");
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .rev("main")
+        .subdir("docs")
+        .persist(branch("output"));
+    let output = run(builder, &["--write", "readme.md", "Changed\n"]);
+    assert!(output.persisted);
+    assert_eq!(
+        git_output(&fixture, &["show", "output:docs/readme.md"]),
+        "Changed\n"
+    );
+    assert_eq!(
+        git_output(&fixture, &["show", "output:hello.txt"]),
+        "Hello\n"
+    );
+}
+
+#[test]
+fn branch_needs_a_revision() {
+    let fixture = fixture_with_identity("persist_branch_no_rev");
+    let result = Runner::builder()
+        .repo(fixture.path("src"))
+        .index()
+        .persist(branch("output"))
+        .component(wasi_ls())
+        .build();
+    assert!(result.is_err());
+}
@@ -0,0 +1,73 @@
+//! Running `wasm32-wasip1` core modules through the preview1 adapter.
+
+mod common;
+
+use common::Fixture;
+use wasmtime_fs_demo::{RunOutcome, RunOutput, Runner, RunnerBuilder};
+
+// A WASIp1 command that prints `hello.txt` from the first preopened
+// directory, or exits with an error if it can't. The adapter can only give a
+// WASIp2 host success or failure, so any non-zero exit code becomes 1.
+const CAT_HELLO: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "path_open"
+    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_read"
+    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+  (memory (export "memory") 1)
+  ;; 0: the path, 16: the opened fd, 20: an iovec, 28: the byte count,
+  ;; 64: the buffer.
+  (data (i32.const 0) "hello.txt")
+  (func (export "_start")
+    ;; Preopens start at fd 3. Rights 2 is `fd_read`.
+    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 9)
+          (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 16))
+      (then (call $proc_exit (i32.const 1))))
+    (i32.store (i32.const 20) (i32.const 64))
+    (i32.store (i32.const 24) (i32.const 64))
+    (if (call $fd_read (i32.load (i32.const 16)) (i32.const 20) (i32.const 1) (i32.const 28))
+      (then (call $proc_exit (i32.const 1))))
+    (i32.store (i32.const 24) (i32.load (i32.const 28)))
+    (if (call $fd_write (i32.const 1) (i32.const 20) (i32.const 1) (i32.const 28))
+      (then (call $proc_exit (i32.const 1))))
+    (call $proc_exit (i32.const 0))))
+"#;
+
+fn run(builder: RunnerBuilder, fixture: &Fixture) -> RunOutput {
+    let module = fixture.path("cat_hello.wasm");
+    std::fs::write(&module, wat::parse_str(CAT_HELLO).unwrap()).unwrap();
+    builder
+        .repo(fixture.path("src"))
+        .component(module)
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap()
+}
+
+#[test]
+fn core_modules_are_adapted() {
+    let fixture = Fixture::new("preview1");
+    let output = run(Runner::builder(), &fixture);
+    assert!(
+        matches!(output.outcome, RunOutcome::Exited(0)),
+        "{:?}",
+        output.outcome
+    );
+    assert_eq!(&output.stdout.unwrap()[..], b"Hello\n");
+}
+
+#[test]
+fn core_module_errors() {
+    let fixture = Fixture::new("preview1_missing");
+    // There's no `hello.txt` in `docs`.
+    let output = run(Runner::builder().subdir("docs"), &fixture);
+    assert!(
+        matches!(output.outcome, RunOutcome::Exited(1)),
+        "{:?}",
+        output.outcome
+    );
+    assert!(output.stdout.unwrap().is_empty());
+}
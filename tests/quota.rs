@@ -0,0 +1,36 @@
+//! Filesystem quotas.
+
+mod common;
+
+use common::{Fixture, wasi_ls};
+use wasmtime_fs_demo::{FsQuota, RunOutcome, Runner};
+
+#[test]
+fn streams_are_counted_and_released() {
+    let fixture = Fixture::new("quota_streams");
+    let run = |max_streams| {
+        Runner::builder()
+            .repo(fixture.path("src"))
+            .component(wasi_ls())
+            .args(["--cat", "hello.txt", "docs/readme.md"])
+            .quota(FsQuota {
+                max_streams: Some(max_streams),
+                ..Default::default()
+            })
+            .capture_output(1 << 20)
+            .run_sync()
+            .unwrap()
+    };
+
+    // Each file is closed before the next is opened.
+    let output = run(1);
+    assert!(matches!(output.outcome, RunOutcome::Exited(0)));
+    assert_eq!(output.stdout.unwrap(), "Hello\n# Readme\n");
+    assert_eq!(output.fs_usage.peak_streams, 1);
+    assert_eq!(output.fs_usage.open_streams, 0);
+
+    let output = run(0);
+    let stdout = String::from_utf8(output.stdout.unwrap().to_vec()).unwrap();
+    assert!(!stdout.contains("Hello"), "{stdout}");
+    assert_eq!(output.fs_usage.peak_streams, 0);
+}
@@ -0,0 +1,162 @@
+//! Running against repositories that aren't a plain checkout.
+
+mod common;
+
+use std::path::Path;
+
+use common::{Fixture, run_ls};
+use wasmtime_fs_demo::{Runner, SharedRepo};
+
+// Run `wasi_ls` against `repo` and return what it printed.
+fn list(repo: &Path) -> String {
+    run_ls(Runner::builder().repo(repo).rev("HEAD"), &[])
+}
+
+fn assert_lists_fixture_files(listing: &str) {
+    assert!(listing.contains("hello.txt"), "{listing}");
+    assert!(listing.contains("docs"), "{listing}");
+    assert!(listing.contains("readme.md"), "{listing}");
+}
+
+#[test]
+fn bare_repository() {
+    let fixture = Fixture::new("bare");
+    fixture.git(&["clone", "--quiet", "--bare", "src", "bare.git"]);
+
+    assert_lists_fixture_files(&list(&fixture.path("bare.git")));
+}
+
+#[test]
+fn alternates() {
+    let fixture = Fixture::new("alternates");
+    // `--shared` sets up `objects/info/alternates` pointing at the source
+    // instead of copying its objects.
+    fixture.git(&["clone", "--quiet", "--shared", "src", "shared"]);
+    assert!(fixture.path("shared/.git/objects/info/alternates").exists());
+
+    assert_lists_fixture_files(&list(&fixture.path("shared")));
+}
+
+#[test]
+fn bare_repository_with_alternates() {
+    let fixture = Fixture::new("bare_alternates");
+    fixture.git(&[
+        "clone",
+        "--quiet",
+        "--bare",
+        "--shared",
+        "src",
+        "mirror.git",
+    ]);
+    assert!(fixture.path("mirror.git/objects/info/alternates").exists());
+
+    assert_lists_fixture_files(&list(&fixture.path("mirror.git")));
+}
+
+#[test]
+fn bundle_with_all_refs() {
+    let fixture = Fixture::new("bundle_all");
+    // Includes HEAD as well as the branch.
+    fixture.git(&[
+        "-C",
+        "src",
+        "bundle",
+        "create",
+        "--quiet",
+        "../all.bundle",
+        "--all",
+    ]);
+
+    assert_lists_fixture_files(&list(&fixture.path("all.bundle")));
+}
+
+#[test]
+fn bundle_without_head() {
+    let fixture = Fixture::new("bundle_no_head");
+    fixture.git(&[
+        "-C",
+        "src",
+        "bundle",
+        "create",
+        "--quiet",
+        "../main.bundle",
+        "main",
+    ]);
+
+    assert_lists_fixture_files(&list(&fixture.path("main.bundle")));
+}
+
+#[test]
+fn sha256_bundles_are_rejected() {
+    let fixture = Fixture::empty("bundle_sha256");
+    fixture.git(&["init", "--quiet", "--object-format=sha256", "sha256"]);
+    fixture.write_files("sha256", &[("hello.txt", "Hello\n")]);
+    fixture.commit("sha256", "Initial commit");
+    fixture.git(&[
+        "-C",
+        "sha256",
+        "bundle",
+        "create",
+        "--quiet",
+        "../sha256.bundle",
+        "--all",
+    ]);
+
+    let error = SharedRepo::open(fixture.path("sha256.bundle"))
+        .err()
+        .unwrap();
+    assert!(
+        format!("{error:#}").contains("unsupported bundle object format \"sha256\""),
+        "{error:#}"
+    );
+}
+
+#[test]
+fn incremental_bundles_are_rejected() {
+    let fixture = Fixture::new("bundle_incremental");
+    fixture.write_files("src", &[("second.txt", "Second\n")]);
+    fixture.commit("src", "Second commit");
+    fixture.git(&[
+        "-C",
+        "src",
+        "bundle",
+        "create",
+        "--quiet",
+        "../inc.bundle",
+        "main~1..main",
+    ]);
+
+    let error = SharedRepo::open(fixture.path("inc.bundle")).err().unwrap();
+    assert!(
+        format!("{error:#}").contains("incremental bundles"),
+        "{error:#}"
+    );
+}
+
+#[test]
+fn partial_clone_without_promisor() {
+    let fixture = Fixture::new("partial_clone");
+    fixture.partial_clone("src", "partial");
+
+    // The trees are there, but not the blobs.
+    let builder = Runner::builder().repo(fixture.path("partial")).rev("HEAD");
+    assert_lists_fixture_files(&run_ls(builder.clone(), &[]));
+    let output = run_ls(builder, &["--print", "hello.txt"]);
+    assert_eq!(output, "--print hello.txt: I/O error (os error 29)\n");
+}
+
+#[test]
+fn partial_clone_with_promisor() {
+    let fixture = Fixture::new("partial_clone_promisor");
+    fixture.partial_clone("src", "partial");
+
+    let repo = SharedRepo::open(fixture.path("partial"))
+        .unwrap()
+        .with_promisor(fixture.path("src"))
+        .unwrap();
+    let output = run_ls(
+        Runner::builder().shared_repo(repo).rev("HEAD"),
+        &["--cat", "hello.txt", "docs/readme.md"],
+    );
+    assert_eq!(output, "Hello\n# Readme\n");
+}
@@ -0,0 +1,121 @@
+//! The `changes` file listing what differs between two revisions.
+
+mod common;
+
+#[cfg(unix)]
+use std::os::unix::fs::{PermissionsExt as _, symlink};
+use std::process::Command;
+
+use common::{Fixture, run_ls};
+use wasmtime_fs_demo::Runner;
+
+fn changes(fixture: &Fixture) -> String {
+    run_ls(
+        Runner::builder()
+            .repo(fixture.path("src"))
+            .side_by_side("HEAD~1", "HEAD", true),
+        &["--cat", "changes"],
+    )
+}
+
+// What Git itself says changed in the last commit.
+fn git_name_status(fixture: &Fixture) -> String {
+    let output = Command::new("git")
+        .args(["diff", "--name-status", "--no-renames", "HEAD~1", "HEAD"])
+        .current_dir(fixture.path("src"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn lists_added_deleted_and_modified_files() {
+    let fixture = Fixture::with_files(
+        "side_by_side_files",
+        &[
+            ("deleted.txt", "deleted\n"),
+            ("modified.txt", "before\n"),
+            ("same.txt", "same\n"),
+            ("dir/nested.txt", "nested\n"),
+        ],
+    );
+    let src = fixture.path("src");
+    std::fs::remove_file(src.join("deleted.txt")).unwrap();
+    std::fs::write(src.join("modified.txt"), "after\n").unwrap();
+    std::fs::write(src.join("added.txt"), "added\n").unwrap();
+    std::fs::write(src.join("dir/nested.txt"), "changed\n").unwrap();
+    fixture.commit("src", "Change files");
+
+    let changes = changes(&fixture);
+    assert_eq!(
+        changes,
+        "A\tadded.txt\nD\tdeleted.txt\nM\tdir/nested.txt\nM\tmodified.txt\n"
+    );
+    assert_eq!(changes, git_name_status(&fixture));
+}
+
+#[test]
+fn replacing_a_file_with_a_directory() {
+    let fixture = Fixture::with_files(
+        "side_by_side_file_to_dir",
+        &[("thing", "file\n"), ("other/inner.txt", "inner\n")],
+    );
+    let src = fixture.path("src");
+    std::fs::remove_file(src.join("thing")).unwrap();
+    std::fs::remove_dir_all(src.join("other")).unwrap();
+    fixture.write_files(
+        "src",
+        &[("thing/inner.txt", "inner\n"), ("other", "file\n")],
+    );
+    fixture.commit("src", "Swap a file and a directory");
+
+    let changes = changes(&fixture);
+    assert_eq!(
+        changes,
+        "A\tother\nD\tother/inner.txt\nD\tthing\nA\tthing/inner.txt\n"
+    );
+    assert_eq!(changes, git_name_status(&fixture));
+}
+
+#[cfg(unix)]
+#[test]
+fn mode_changes_and_symlinks() {
+    let fixture = Fixture::with_files(
+        "side_by_side_modes",
+        &[
+            ("script.sh", "echo hi\n"),
+            ("becomes_link", "file\n"),
+            ("target.txt", "target\n"),
+            ("other.txt", "other\n"),
+        ],
+    );
+    let src = fixture.path("src");
+    symlink("target.txt", src.join("link")).unwrap();
+    symlink("target.txt", src.join("becomes_file")).unwrap();
+    fixture.commit("src", "Add links");
+
+    // Only the executable bit changes.
+    let script = src.join("script.sh");
+    let mut permissions = std::fs::metadata(&script).unwrap().permissions();
+    permissions.set_mode(0o755);
+    std::fs::set_permissions(&script, permissions).unwrap();
+    // A symlink that points somewhere else.
+    std::fs::remove_file(src.join("link")).unwrap();
+    symlink("other.txt", src.join("link")).unwrap();
+    // A file replaced by a symlink, and the other way round.
+    std::fs::remove_file(src.join("becomes_link")).unwrap();
+    symlink("target.txt", src.join("becomes_link")).unwrap();
+    std::fs::remove_file(src.join("becomes_file")).unwrap();
+    std::fs::write(src.join("becomes_file"), "file\n").unwrap();
+    // A new symlink.
+    symlink("target.txt", src.join("new_link")).unwrap();
+    fixture.commit("src", "Change modes");
+
+    let changes = changes(&fixture);
+    assert_eq!(
+        changes,
+        "T\tbecomes_file\nT\tbecomes_link\nM\tlink\nA\tnew_link\nM\tscript.sh\n"
+    );
+    assert_eq!(changes, git_name_status(&fixture));
+}
@@ -0,0 +1,122 @@
+//! Restricting the tree with sparse-checkout style patterns.
+
+mod common;
+
+use common::{Fixture, run_ls};
+use gix::bstr::ByteSlice as _;
+use wasmtime_fs_demo::{Runner, SparsePatterns};
+
+fn includes(patterns: &SparsePatterns, path: &str) -> bool {
+    patterns.includes_file(path.as_bytes().as_bstr())
+}
+
+#[test]
+fn cone_includes_files_next_to_its_parents() {
+    let patterns = SparsePatterns::cone(["src/app"]);
+    assert!(includes(&patterns, "README.md"));
+    assert!(includes(&patterns, "src/lib.rs"));
+    assert!(includes(&patterns, "src/app/main.rs"));
+    assert!(includes(&patterns, "src/app/deep/nested/file.rs"));
+}
+
+#[test]
+fn cone_excludes_sibling_directories() {
+    let patterns = SparsePatterns::cone(["src/app/"]);
+    assert!(!includes(&patterns, "src/other/main.rs"));
+    assert!(!includes(&patterns, "docs/index.md"));
+    // Only a whole path component counts.
+    assert!(!includes(&patterns, "src/application/main.rs"));
+}
+
+#[test]
+fn nested_cones() {
+    let patterns = SparsePatterns::cone(["a", "a/b/c"]);
+    // Everything inside `a` is included anyway.
+    assert!(includes(&patterns, "a/file"));
+    assert!(includes(&patterns, "a/x/file"));
+    assert!(includes(&patterns, "a/b/c/file"));
+    assert!(!includes(&patterns, "b/file"));
+}
+
+#[test]
+fn full_patterns_can_exclude_again() {
+    let patterns = SparsePatterns::full("/*\n!/*/\ndocs/\n!docs/private/\n").unwrap();
+    assert!(includes(&patterns, "README.md"));
+    assert!(!includes(&patterns, "src/lib.rs"));
+    assert!(includes(&patterns, "docs/index.md"));
+    assert!(!includes(&patterns, "docs/private/notes.md"));
+}
+
+#[test]
+fn full_directory_patterns_include_everything_below() {
+    let patterns = SparsePatterns::full("docs/\n").unwrap();
+    assert!(includes(&patterns, "docs/index.md"));
+    assert!(includes(&patterns, "docs/a/b/c.md"));
+    assert!(includes(&patterns, "nested/docs/index.md"));
+    // A file called `docs` isn't a directory.
+    assert!(!includes(&patterns, "docs"));
+    assert!(!includes(&patterns, "README.md"));
+}
+
+fn fixture(name: &str) -> Fixture {
+    Fixture::with_files(
+        name,
+        &[
+            ("top.txt", "top\n"),
+            ("src/lib.rs", "lib\n"),
+            ("src/app/main.rs", "main\n"),
+            ("src/other/main.rs", "other\n"),
+            ("docs/index.md", "index\n"),
+            ("docs/private/notes.md", "notes\n"),
+        ],
+    )
+}
+
+const ALL_FILES: [&str; 6] = [
+    "top.txt",
+    "src/lib.rs",
+    "src/app/main.rs",
+    "src/other/main.rs",
+    "docs/index.md",
+    "docs/private/notes.md",
+];
+
+fn cat_all(fixture: &Fixture, patterns: SparsePatterns) -> String {
+    let mut args = vec!["--cat"];
+    args.extend(ALL_FILES);
+    run_ls(
+        Runner::builder().repo(fixture.path("src")).sparse(patterns),
+        &args,
+    )
+}
+
+#[test]
+fn cone_tree() {
+    let fixture = fixture("sparse_cone_tree");
+    let output = cat_all(&fixture, SparsePatterns::cone(["src/app"]));
+    assert_eq!(
+        output,
+        "top\n\
+         lib\n\
+         main\n\
+         src/other/main.rs: NotFound\n\
+         docs/index.md: NotFound\n\
+         docs/private/notes.md: NotFound\n"
+    );
+}
+
+#[test]
+fn full_tree() {
+    let fixture = fixture("sparse_full_tree");
+    let patterns = SparsePatterns::full("docs/\n!docs/private/\n").unwrap();
+    let output = cat_all(&fixture, patterns);
+    assert_eq!(
+        output,
+        "top.txt: NotFound\n\
+         src/lib.rs: NotFound\n\
+         src/app/main.rs: NotFound\n\
+         src/other/main.rs: NotFound\n\
+         index\n\
+         docs/private/notes.md: NotFound\n"
+    );
+}
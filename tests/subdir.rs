@@ -0,0 +1,89 @@
+//! Mounting a subdirectory of the tree as the root.
+
+mod common;
+
+use common::{Fixture, run_ls, wasi_ls};
+use wasmtime_fs_demo::Runner;
+
+#[test]
+fn subdir_is_the_root() {
+    let fixture = Fixture::new("subdir");
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .rev("HEAD")
+        .subdir("docs");
+    let output = run_ls(builder, &["--cat", "readme.md", "hello.txt"]);
+    assert_eq!(output, "# Readme\nhello.txt: NotFound\n");
+}
+
+#[test]
+fn subdir_must_be_a_directory() {
+    let fixture = Fixture::new("subdir_not_a_directory");
+    for (subdir, message) in [
+        ("hello.txt", "is not a directory"),
+        ("missing", "not found"),
+        ("docs/missing", "not found"),
+    ] {
+        let result = Runner::builder()
+            .repo(fixture.path("src"))
+            .rev("HEAD")
+            .subdir(subdir)
+            .component(wasi_ls())
+            .build();
+        let Err(error) = result else {
+            panic!("built a runner for {subdir}");
+        };
+        assert!(format!("{error:#}").contains(message), "{error:#}");
+    }
+}
+
+#[test]
+fn parent_of_the_root_is_forbidden() {
+    let fixture = Fixture::new("subdir_parent");
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .rev("HEAD")
+        .subdir("docs");
+    let output = run_ls(
+        builder,
+        &["--cat", "../hello.txt", "../docs/readme.md", "./readme.md"],
+    );
+    assert_eq!(
+        output,
+        "../hello.txt: PermissionDenied\n\
+         ../docs/readme.md: PermissionDenied\n\
+         # Readme\n"
+    );
+}
+
+#[test]
+fn side_by_side_subdirs() {
+    let fixture = Fixture::new("subdir_side_by_side");
+    fixture.write_files(
+        "src",
+        &[
+            ("docs/readme.md", "Changed\n"),
+            ("hello.txt", "Also changed\n"),
+        ],
+    );
+    fixture.commit("src", "Change");
+
+    let builder = Runner::builder()
+        .repo(fixture.path("src"))
+        .side_by_side("HEAD~1", "HEAD", true)
+        .subdir("docs");
+    let output = run_ls(
+        builder,
+        &[
+            "--cat",
+            "a/readme.md",
+            "b/readme.md",
+            "changes",
+            "a/hello.txt",
+        ],
+    );
+    assert_eq!(
+        output,
+        "# Readme\nChanged\nM\treadme.md\na/hello.txt: NotFound\n"
+    );
+}
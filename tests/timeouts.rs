@@ -0,0 +1,77 @@
+//! Wall-clock timeouts.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{Fixture, wasi_ls};
+use wasmtime_fs_demo::{RunOutcome, Runner};
+
+// Sleeping blocks in a host call (polling a clock), where the epoch deadline
+// can't interrupt it.
+#[tokio::test]
+async fn timeout_interrupts_host_calls() {
+    let fixture = Fixture::new("timeout_sleep");
+    let start = Instant::now();
+    let output = Runner::builder()
+        .repo(fixture.path("src"))
+        .component(wasi_ls())
+        .args(["--sleep", "60"])
+        .timeout(Duration::from_millis(200))
+        .run()
+        .await
+        .unwrap();
+    assert!(
+        matches!(output.outcome, RunOutcome::TimedOut),
+        "{:?}",
+        output.outcome
+    );
+    assert!(start.elapsed() < Duration::from_secs(30));
+}
+
+// Without yielding, a component that never makes a host call would hold on to
+// the only worker thread and the timeout would never get a chance to fire.
+#[tokio::test]
+async fn async_runs_yield_while_spinning() {
+    let fixture = Fixture::new("timeout_spin_async");
+    let output = Runner::builder()
+        .repo(fixture.path("src"))
+        .component(wasi_ls())
+        .args(["--spin"])
+        .timeout(Duration::from_millis(200))
+        .run()
+        .await
+        .unwrap();
+    assert!(
+        matches!(output.outcome, RunOutcome::TimedOut),
+        "{:?}",
+        output.outcome
+    );
+}
+
+#[test]
+fn sync_runs_time_out_separately() {
+    let fixture = Fixture::new("timeout_spin_sync");
+    let runner = Runner::builder()
+        .repo(fixture.path("src"))
+        .component(wasi_ls())
+        .args(["--spin"])
+        .timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+    // Compile first so that both runs start together.
+    let _ = runner.run_sync();
+
+    // The runs share an engine, but the first one timing out mustn't stop the
+    // second one early.
+    let first = std::thread::spawn({
+        let runner = runner.clone();
+        move || runner.run_sync().unwrap()
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    let second = runner.run_sync().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(190));
+    assert!(matches!(second.outcome, RunOutcome::TimedOut));
+    assert!(matches!(first.join().unwrap().outcome, RunOutcome::TimedOut));
+}
@@ -0,0 +1,93 @@
+//! Running against the files in the working tree instead of a commit.
+
+mod common;
+
+use common::{Fixture, run_ls};
+use wasmtime_fs_demo::Runner;
+
+// A checkout whose files are older than its index, so their stat info isn't
+// racy and unchanged files are taken from the index without reading them.
+fn fixture(name: &str) -> Fixture {
+    let fixture = Fixture::with_files(
+        name,
+        &[
+            ("same.txt", "same\n"),
+            ("changed.txt", "before\n"),
+            ("gone.txt", "gone\n"),
+        ],
+    );
+    let src = fixture.path("src");
+    let status = std::process::Command::new("touch")
+        .args(["-d", "2000-01-01", "same.txt", "changed.txt", "gone.txt"])
+        .current_dir(&src)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fixture.git(&["-C", "src", "update-index", "--refresh"]);
+    fixture
+}
+
+#[test]
+fn sees_unstaged_changes() {
+    let fixture = fixture("worktree_changes");
+    let src = fixture.path("src");
+    std::fs::write(src.join("changed.txt"), "after\n").unwrap();
+    std::fs::remove_file(src.join("gone.txt")).unwrap();
+    std::fs::write(src.join("new.txt"), "new\n").unwrap();
+
+    let output = run_ls(
+        Runner::builder().repo(&src).worktree(false),
+        &["--cat", "same.txt", "changed.txt", "gone.txt", "new.txt"],
+    );
+    assert_eq!(
+        output,
+        "same\nafter\ngone.txt: NotFound\nnew.txt: NotFound\n"
+    );
+}
+
+#[test]
+fn includes_untracked_files() {
+    let fixture = fixture("worktree_untracked");
+    let src = fixture.path("src");
+    std::fs::write(src.join(".gitignore"), "*.log\n").unwrap();
+    std::fs::write(src.join("new.txt"), "new\n").unwrap();
+    std::fs::write(src.join("debug.log"), "noise\n").unwrap();
+
+    let output = run_ls(
+        Runner::builder().repo(&src).worktree(true),
+        &["--cat", "same.txt", "new.txt", "debug.log"],
+    );
+    assert_eq!(output, "same\nnew\ndebug.log: NotFound\n");
+}
+
+#[test]
+fn unchanged_files_are_read_if_they_are_converted() {
+    let fixture = Fixture::with_files(
+        "worktree_eol",
+        &[
+            (".gitattributes", "* text eol=crlf\n"),
+            ("same.txt", "same\n"),
+            ("changed.txt", "before\n"),
+        ],
+    );
+    // Check the files out again so they have CRLF line endings, then make
+    // them old so their stat info isn't racy.
+    let src = fixture.path("src");
+    std::fs::remove_file(src.join("same.txt")).unwrap();
+    std::fs::remove_file(src.join("changed.txt")).unwrap();
+    fixture.git(&["-C", "src", "checkout", "--", "."]);
+    let status = std::process::Command::new("touch")
+        .args(["-d", "2000-01-01", "same.txt", "changed.txt"])
+        .current_dir(&src)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fixture.git(&["-C", "src", "update-index", "--refresh"]);
+    std::fs::write(src.join("changed.txt"), "after\r\n").unwrap();
+
+    let output = run_ls(
+        Runner::builder().repo(&src).worktree(false),
+        &["--cat", "same.txt", "changed.txt"],
+    );
+    assert_eq!(output, "same\r\nafter\r\n");
+}
@@ -0,0 +1,599 @@
+//! Writing files in an overlay, and exporting the changes.
+
+mod common;
+
+use std::process::Command;
+
+use common::{Fixture, run_ls, wasi_ls};
+use wasmtime_fs_demo::{Changes, FileMode, FsQuota, RunOutcome, Runner, RunnerBuilder};
+
+// Run `wasi_ls` with write commands and return what it printed and what it
+// changed.
+fn run_writes(builder: RunnerBuilder, args: &[&str]) -> (String, Changes) {
+    let output = builder
+        .writable()
+        .component(wasi_ls())
+        .args(args)
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap();
+    assert!(
+        matches!(output.outcome, RunOutcome::Exited(0)),
+        "{:?}",
+        output.outcome
+    );
+    let stdout = String::from_utf8(output.stdout.unwrap().to_vec()).unwrap();
+    (stdout, output.changes.unwrap())
+}
+
+fn changed_paths(changes: &Changes) -> Vec<String> {
+    changes
+        .files()
+        .iter()
+        .map(|file| file.path.to_string())
+        .collect()
+}
+
+// What `git status --porcelain` says about a checkout.
+fn git_status(fixture: &Fixture, dir: &str) -> String {
+    let output = Command::new("git")
+        .args(["-C", dir, "status", "--porcelain", "--untracked-files=all"])
+        .current_dir(fixture.path(""))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn read_only_by_default() {
+    let fixture = Fixture::new("writable_read_only");
+    let output = Runner::builder()
+        .repo(fixture.path("src"))
+        .component(wasi_ls())
+        .args(["--write", "new.txt", "Hi\n", "--mkdir", "dir"])
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout.unwrap().to_vec()).unwrap(),
+        "--write new.txt: Read-only file system (os error 69)\n\
+         --mkdir dir: Read-only file system (os error 69)\n"
+    );
+    assert!(output.changes.is_none());
+}
+
+#[test]
+fn writes_are_visible_during_the_run() {
+    let fixture = Fixture::new("writable_visible");
+    let builder = Runner::builder().repo(fixture.path("src"));
+    let (stdout, changes) = run_writes(
+        builder,
+        &[
+            "--write",
+            "new.txt",
+            "New\n",
+            "--append",
+            "hello.txt",
+            "Again\n",
+            "--mkdir",
+            "dir",
+            "--write",
+            "dir/nested.txt",
+            "Nested\n",
+            "--print",
+            "new.txt",
+            "--print",
+            "hello.txt",
+            "--print",
+            "dir/nested.txt",
+        ],
+    );
+    assert_eq!(stdout, "New\nHello\nAgain\nNested\n");
+    assert_eq!(
+        changed_paths(&changes),
+        ["dir/nested.txt", "hello.txt", "new.txt"]
+    );
+    let hello = &changes.files()[1];
+    assert!(hello.old.is_some());
+    assert_eq!(
+        hello.new.as_ref().unwrap(),
+        &(FileMode::Regular, "Hello\nAgain\n".into())
+    );
+}
+
+#[test]
+fn each_run_starts_from_the_commit() {
+    let fixture = Fixture::new("writable_each_run");
+    let builder = Runner::builder().repo(fixture.path("src")).writable();
+    let output = builder
+        .component(wasi_ls())
+        .args([
+            "--mkdir",
+            "docs/new",
+            "--write",
+            "docs/new/a.txt",
+            "A\n",
+            "--rm",
+            "hello.txt",
+        ])
+        .run_sync()
+        .unwrap();
+    assert!(matches!(output.outcome, RunOutcome::Exited(0)));
+
+    // Each run starts from the commit again.
+    let tree = run_ls(Runner::builder().repo(fixture.path("src")).writable(), &[]);
+    assert!(tree.contains("hello.txt"));
+    assert!(!tree.contains("a.txt"));
+}
+
+#[test]
+fn removing_and_renaming() {
+    let fixture = Fixture::with_files(
+        "writable_rename",
+        &[
+            ("hello.txt", "Hello\n"),
+            ("docs/readme.md", "# Readme\n"),
+            ("docs/guide/intro.md", "Intro\n"),
+        ],
+    );
+    let builder = Runner::builder().repo(fixture.path("src"));
+    let (stdout, changes) = run_writes(
+        builder,
+        &[
+            "--rmdir",
+            "docs",
+            "--rm",
+            "docs",
+            "--mv",
+            "docs",
+            "docs/guide/docs",
+            "--mv",
+            "hello.txt",
+            "docs",
+            "--mv",
+            "docs",
+            "manual",
+            "--rm",
+            "manual/guide/intro.md",
+            "--rmdir",
+            "manual/guide",
+            "--rm",
+            "missing.txt",
+            "--print",
+            "manual/readme.md",
+        ],
+    );
+    assert_eq!(
+        stdout,
+        "--rmdir docs: Directory not empty (os error 55)\n\
+         --rm docs: Is a directory (os error 31)\n\
+         --mv docs: Invalid argument (os error 28)\n\
+         --mv hello.txt: Is a directory (os error 31)\n\
+         --rm missing.txt: No such file or directory (os error 44)\n\
+         # Readme\n"
+    );
+    assert_eq!(
+        changed_paths(&changes),
+        ["docs/guide/intro.md", "docs/readme.md", "manual/readme.md"]
+    );
+    assert!(changes.files()[0].new.is_none());
+    assert_eq!(changes.files()[2].new.as_ref().unwrap().1, "# Readme\n");
+}
+
+#[test]
+fn writes_count_against_the_quota() {
+    let fixture = Fixture::new("writable_quota");
+    let output = Runner::builder()
+        .repo(fixture.path("src"))
+        .writable()
+        .quota(FsQuota {
+            max_bytes_written: Some(8),
+            ..FsQuota::default()
+        })
+        .component(wasi_ls())
+        .args([
+            "--write", "a.txt", "12345", "--write", "b.txt", "12345", "--append", "a.txt", "678",
+        ])
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap();
+    // The stream fails with `quota`, but wasi-libc reports that as `EIO`.
+    let stdout = String::from_utf8(output.stdout.unwrap().to_vec()).unwrap();
+    assert!(stdout.starts_with("--write b.txt: "), "{stdout}");
+    assert_eq!(output.fs_usage.bytes_written, 8);
+    let changes = output.changes.unwrap();
+    let a = &changes.files()[0];
+    assert_eq!(a.path, "a.txt");
+    assert_eq!(a.new.as_ref().unwrap().1, "12345678");
+}
+
+#[test]
+fn unchanged_files_are_not_read() {
+    let fixture = Fixture::new("writable_partial_clone");
+    fixture.partial_clone("src", "partial");
+    // None of the blobs are there, but only the new file needs reading.
+    let builder = Runner::builder().repo(fixture.path("partial"));
+    let (_, changes) = run_writes(builder, &["--write", "new.txt", "New\n"]);
+    assert_eq!(changed_paths(&changes), ["new.txt"]);
+}
+
+#[test]
+fn copying_a_file_to_change_it_counts_against_the_quota() {
+    let fixture = Fixture::new("writable_copy_quota");
+    let output = Runner::builder()
+        .repo(fixture.path("src"))
+        .writable()
+        .quota(FsQuota {
+            max_bytes_written: Some(4),
+            ..FsQuota::default()
+        })
+        .component(wasi_ls())
+        .args(["--append", "hello.txt", "!"])
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout.unwrap().to_vec()).unwrap();
+    assert!(stdout.starts_with("--append hello.txt: "), "{stdout}");
+    assert_eq!(output.fs_usage.bytes_written, 0);
+    assert!(output.changes.unwrap().is_empty());
+}
+
+#[test]
+fn failed_opens_change_nothing() {
+    let fixture = Fixture::new("writable_descriptor_quota");
+    let output = Runner::builder()
+        .repo(fixture.path("src"))
+        .writable()
+        .quota(FsQuota {
+            // Just the preopen.
+            max_descriptors: Some(1),
+            ..FsQuota::default()
+        })
+        .component(wasi_ls())
+        .args([
+            "--write",
+            "new.txt",
+            "New\n",
+            "--write",
+            "hello.txt",
+            "Changed\n",
+        ])
+        .capture_output(1 << 20)
+        .run_sync()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout.unwrap().to_vec()).unwrap();
+    assert_eq!(stdout.lines().count(), 2, "{stdout}");
+    assert!(output.changes.unwrap().is_empty());
+}
+
+#[test]
+fn changes_are_kept_whatever_the_exit_code() {
+    let fixture = Fixture::new("writable_exit_code");
+    let output = Runner::builder()
+        .repo(fixture.path("src"))
+        .writable()
+        .component(wasi_ls())
+        .args(["--write", "new.txt", "New\n", "--exit", "1"])
+        .run_sync()
+        .unwrap();
+    assert!(matches!(output.outcome, RunOutcome::Exited(1)));
+    assert_eq!(changed_paths(&output.changes.unwrap()), ["new.txt"]);
+}
+
+#[test]
+fn unchanged_files_are_not_listed() {
+    let fixture = Fixture::new("writable_unchanged");
+    let builder = Runner::builder().repo(fixture.path("src"));
+    let (_, changes) = run_writes(
+        builder,
+        &[
+            "--write",
+            "hello.txt",
+            "Hello\n",
+            "--mv",
+            "docs",
+            "moved",
+            "--mv",
+            "moved",
+            "docs",
+            "--mkdir",
+            "empty",
+        ],
+    );
+    assert!(changes.is_empty(), "{changes:?}");
+}
+
+#[tokio::test]
+async fn async_runs_have_changes_too() {
+    let fixture = Fixture::new("writable_async");
+    let output = Runner::builder()
+        .repo(fixture.path("src"))
+        .writable()
+        .component(wasi_ls())
+        .args(["--write", "docs/readme.md", "Changed\n"])
+        .run()
+        .await
+        .unwrap();
+    assert!(matches!(output.outcome, RunOutcome::Exited(0)));
+    assert_eq!(changed_paths(&output.changes.unwrap()), ["docs/readme.md"]);
+}
+
+#[test]
+fn gitinfo_stays_read_only() {
+    let fixture = Fixture::new("writable_gitinfo");
+    let builder = Runner::builder().repo(fixture.path("src")).git_info();
+    let (stdout, changes) = run_writes(
+        builder,
+        &[
+            "--write",
+            ".gitinfo/HEAD",
+            "Changed\n",
+            "--write",
+            ".gitinfo/new",
+            "New\n",
+            "--rm",
+            ".gitinfo/refs",
+            "--mv",
+            ".gitinfo/commit",
+            "commit",
+            "--mkdir",
+            ".gitinfo/dir",
+            "--write",
+            "new.txt",
+            "New\n",
+        ],
+    );
+    assert_eq!(
+        stdout,
+        "--write .gitinfo/HEAD: Read-only file system (os error 69)\n\
+         --write .gitinfo/new: Read-only file system (os error 69)\n\
+         --rm .gitinfo/refs: Read-only file system (os error 69)\n\
+         --mv .gitinfo/commit: Read-only file system (os error 69)\n\
+         --mkdir .gitinfo/dir: Read-only file system (os error 69)\n"
+    );
+    assert_eq!(changed_paths(&changes), ["new.txt"]);
+}
+
+#[test]
+fn not_with_gitattributes() {
+    let fixture = Fixture::new("writable_gitattributes");
+    let result = Runner::builder()
+        .repo(fixture.path("src"))
+        .writable()
+        .apply_gitattributes()
+        .component(wasi_ls())
+        .build();
+    assert!(result.is_err());
+}
+
+fn make_executable(fixture: &Fixture, path: &str) {
+    use std::os::unix::fs::PermissionsExt as _;
+    let permissions = std::fs::Permissions::from_mode(0o755);
+    std::fs::set_permissions(fixture.path(path), permissions).unwrap();
+    fixture.commit("src", "Make executable");
+}
+
+// A file long enough for the diff to need more than one hunk.
+fn numbered_lines(count: usize) -> String {
+    (1..=count).map(|n| format!("line {n}\n")).collect()
+}
+
+// Make changes that cover the different kinds of diff, and apply them to a
+// clone of the repository with `git apply`.
+#[test]
+fn unified_diff_applies_with_git() {
+    let long = numbered_lines(30);
+    let fixture = Fixture::with_files(
+        "writable_diff",
+        &[
+            ("long.txt", &long),
+            ("no_newline.txt", "one\ntwo"),
+            ("deleted.txt", "Deleted\n"),
+            ("dir/moved.txt", "Moved\n"),
+        ],
+    );
+    make_executable(&fixture, "src/no_newline.txt");
+    fixture.git(&["clone", "--quiet", "src", "dest"]);
+
+    let changed_long = long
+        .replace("line 2\n", "line two\n")
+        .replace("line 28\n", "")
+        .replace("line 15\n", "line 15\ninserted\n");
+    let builder = Runner::builder().repo(fixture.path("src"));
+    let (_, changes) = run_writes(
+        builder,
+        &[
+            "--write",
+            "long.txt",
+            &changed_long,
+            "--write",
+            "no_newline.txt",
+            "one\nTwo",
+            "--rm",
+            "deleted.txt",
+            "--mv",
+            "dir",
+            "renamed",
+            "--write",
+            "added.txt",
+            "Added\nfile",
+            "--write",
+            "empty.txt",
+            "",
+        ],
+    );
+    let diff = String::from_utf8(changes.unified_diff().unwrap()).unwrap();
+    assert!(diff.contains("\\ No newline at end of file\n"), "{diff}");
+    // Three in `long.txt` and one in each of the other files with contents.
+    assert_eq!(diff.matches("@@ -").count(), 8, "{diff}");
+
+    let patch = fixture.path("changes.patch");
+    std::fs::write(&patch, diff).unwrap();
+    fixture.git(&["-C", "dest", "apply", patch.to_str().unwrap()]);
+
+    let dest = fixture.path("dest");
+    assert_eq!(
+        std::fs::read_to_string(dest.join("long.txt")).unwrap(),
+        changed_long
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("no_newline.txt")).unwrap(),
+        "one\nTwo"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("added.txt")).unwrap(),
+        "Added\nfile"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("renamed/moved.txt")).unwrap(),
+        "Moved\n"
+    );
+    assert!(dest.join("empty.txt").exists());
+    assert!(!dest.join("deleted.txt").exists());
+    assert!(!dest.join("dir").exists());
+}
+
+#[test]
+fn binary_files_are_not_diffed() {
+    let fixture = Fixture::with_files("writable_diff_binary", &[("data.bin", "a\0b")]);
+    let builder = Runner::builder().repo(fixture.path("src"));
+    let (_, changes) = run_writes(builder, &["--write", "data.bin", "b\0c"]);
+    assert_eq!(
+        String::from_utf8(changes.unified_diff().unwrap()).unwrap(),
+        "diff --git a/data.bin b/data.bin\n\
+         Binary files a/data.bin and b/data.bin differ\n"
+    );
+}
+
+#[test]
+fn modes_are_kept() {
+    let fixture = Fixture::new("writable_diff_mode");
+    make_executable(&fixture, "src/hello.txt");
+    let builder = Runner::builder().repo(fixture.path("src"));
+    let (_, changes) = run_writes(builder, &["--write", "hello.txt", "Hi\n"]);
+    // Rewriting a file keeps its mode.
+    assert_eq!(
+        changes.files()[0].new.as_ref().unwrap().0,
+        FileMode::Executable
+    );
+    let diff = String::from_utf8(changes.unified_diff().unwrap()).unwrap();
+    assert_eq!(
+        diff,
+        "diff --git a/hello.txt b/hello.txt\n\
+         --- a/hello.txt\n\
+         +++ b/hello.txt\n\
+         @@ -1 +1 @@\n\
+         -Hello\n\
+         +Hi\n"
+    );
+}
+
+#[test]
+fn write_to_directory_matches_the_run() {
+    let fixture = Fixture::with_files(
+        "writable_directory",
+        &[
+            ("hello.txt", "Hello\n"),
+            ("docs/readme.md", "# Readme\n"),
+            ("docs/guide/intro.md", "Intro\n"),
+        ],
+    );
+    fixture.git(&["clone", "--quiet", "src", "dest"]);
+    let builder = Runner::builder().repo(fixture.path("src"));
+    let (_, changes) = run_writes(
+        builder,
+        &[
+            "--write",
+            "hello.txt",
+            "Changed\n",
+            "--mv",
+            "docs/guide",
+            "guide",
+            "--rm",
+            "docs/readme.md",
+            "--rmdir",
+            "docs",
+            "--write",
+            "docs",
+            "Now a file\n",
+            "--mkdir",
+            "new",
+            "--write",
+            "new/file.txt",
+            "New\n",
+        ],
+    );
+    changes.write_to_directory(fixture.path("dest")).unwrap();
+
+    let dest = fixture.path("dest");
+    assert_eq!(
+        std::fs::read_to_string(dest.join("hello.txt")).unwrap(),
+        "Changed\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("docs")).unwrap(),
+        "Now a file\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("guide/intro.md")).unwrap(),
+        "Intro\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("new/file.txt")).unwrap(),
+        "New\n"
+    );
+    assert_eq!(
+        git_status(&fixture, "dest"),
+        " D docs/guide/intro.md\n \
+         D docs/readme.md\n \
+         M hello.txt\n\
+         ?? docs\n\
+         ?? guide/intro.md\n\
+         ?? new/file.txt\n"
+    );
+}
+
+#[test]
+fn tar_has_added_and_changed_files() {
+    let fixture = Fixture::new("writable_tar");
+    let long_name = format!("{}/file.txt", "d".repeat(120));
+    let builder = Runner::builder().repo(fixture.path("src"));
+    let (_, changes) = run_writes(
+        builder,
+        &[
+            "--write",
+            "hello.txt",
+            "Changed\n",
+            "--rm",
+            "docs/readme.md",
+            "--mkdir",
+            &"d".repeat(120),
+            "--write",
+            &long_name,
+            "Long\n",
+        ],
+    );
+    let mut tar = Vec::new();
+    changes.write_tar(&mut tar).unwrap();
+    assert_eq!(tar.len() % 512, 0);
+
+    let out = fixture.path("out");
+    std::fs::create_dir(&out).unwrap();
+    std::fs::write(fixture.path("changes.tar"), &tar).unwrap();
+    let status = Command::new("tar")
+        .args(["-x", "-f", "../changes.tar"])
+        .current_dir(&out)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        std::fs::read_to_string(out.join("hello.txt")).unwrap(),
+        "Changed\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(out.join(&long_name)).unwrap(),
+        "Long\n"
+    );
+    assert!(!out.join("docs").exists());
+}
@@ -1,8 +1,100 @@
 use std::fs;
+use std::io::Write as _;
 use std::path::Path;
 
+// With no arguments, print the tree of the current directory. Otherwise
+// print the trees of the given paths, or with `--cat` print the files'
+// contents (or the error kind if they can't be read). `--sleep SECONDS` and
+// `--spin` (which loops forever) are for testing timeouts. `--grow PAGES...`
+// grows the memory by each number of 64 KiB pages in turn, printing what
+// `memory.grow` returns, for testing memory limits.
+//
+// For testing writes, a list of `--write PATH TEXT`, `--append PATH TEXT`,
+// `--mkdir PATH`, `--rm PATH`, `--rmdir PATH`, `--mv FROM TO`, `--print PATH`
+// and `--exit CODE` are run in order, printing the error of any that fail.
 fn main() {
-    print_tree(Path::new("."), &mut Vec::new());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|flag| arity(flag).is_some()) {
+        run_commands(&args);
+        return;
+    }
+    match args.split_first() {
+        None => print_tree(Path::new("."), &mut Vec::new()),
+        Some((flag, [seconds])) if flag == "--sleep" => {
+            std::thread::sleep(std::time::Duration::from_secs(seconds.parse().unwrap()));
+        }
+        Some((flag, [])) if flag == "--spin" => {
+            let mut count = 0u64;
+            loop {
+                count = std::hint::black_box(count.wrapping_add(1));
+            }
+        }
+        Some((flag, pages)) if flag == "--grow" => {
+            for pages in pages {
+                println!("{}", memory_grow(pages.parse().unwrap()));
+            }
+        }
+        Some((flag, paths)) if flag == "--cat" => {
+            for path in paths {
+                match fs::read(path) {
+                    Ok(data) => print!("{}", String::from_utf8_lossy(&data)),
+                    Err(error) => println!("{path}: {:?}", error.kind()),
+                }
+            }
+        }
+        Some(_) => {
+            for path in &args {
+                print_tree(Path::new(path), &mut Vec::new());
+            }
+        }
+    }
+}
+
+// What `memory.grow` returns: the old size in pages, or -1 if it failed.
+#[cfg(target_arch = "wasm32")]
+fn memory_grow(pages: usize) -> isize {
+    core::arch::wasm32::memory_grow(0, pages) as isize
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn memory_grow(_pages: usize) -> isize {
+    unimplemented!("--grow only works in WebAssembly")
+}
+
+// How many arguments a write command takes.
+fn arity(flag: &str) -> Option<usize> {
+    match flag {
+        "--write" | "--append" | "--mv" => Some(2),
+        "--mkdir" | "--rm" | "--rmdir" | "--print" | "--exit" => Some(1),
+        _ => None,
+    }
+}
+
+fn run_commands(mut args: &[String]) {
+    while let Some((flag, rest)) = args.split_first() {
+        let arity = arity(flag).unwrap_or_else(|| panic!("unknown command {flag}"));
+        let (params, remaining) = rest.split_at(arity);
+        args = remaining;
+        let result = match (flag.as_str(), params) {
+            ("--write", [path, text]) => fs::write(path, text),
+            ("--append", [path, text]) => fs::OpenOptions::new()
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(text.as_bytes())),
+            ("--mkdir", [path]) => fs::create_dir(path),
+            ("--rm", [path]) => fs::remove_file(path),
+            ("--rmdir", [path]) => fs::remove_dir(path),
+            ("--mv", [from, to]) => fs::rename(from, to),
+            ("--print", [path]) => {
+                fs::read(path).map(|data| print!("{}", String::from_utf8_lossy(&data)))
+            }
+            ("--exit", [code]) => std::process::exit(code.parse().unwrap()),
+            _ => unreachable!(),
+        };
+        if let Err(error) = result {
+            println!("{flag} {}: {error}", params[0]);
+        }
+    }
 }
 
 fn print_tree(path: &Path, is_last_child: &mut Vec<bool>) {
@@ -33,17 +125,17 @@ fn print_tree(path: &Path, is_last_child: &mut Vec<bool>) {
         println!();
     }
 
-    if path.is_dir() {
-        if let Ok(read_dir) = fs::read_dir(path) {
-            let mut entries: Vec<_> = read_dir.filter_map(|e| e.ok()).collect();
-            entries.sort_by_key(|e| e.path());
+    if path.is_dir()
+        && let Ok(read_dir) = fs::read_dir(path)
+    {
+        let mut entries: Vec<_> = read_dir.filter_map(|e| e.ok()).collect();
+        entries.sort_by_key(|e| e.path());
 
-            let len = entries.len();
-            for (i, entry) in entries.into_iter().enumerate() {
-                is_last_child.push(i == len - 1);
-                print_tree(&entry.path(), is_last_child);
-                is_last_child.pop();
-            }
+        let len = entries.len();
+        for (i, entry) in entries.into_iter().enumerate() {
+            is_last_child.push(i == len - 1);
+            print_tree(&entry.path(), is_last_child);
+            is_last_child.pop();
         }
     }
 }